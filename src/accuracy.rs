@@ -0,0 +1,141 @@
+//! Prediction error by lead time, computed from the prediction history kept by
+//! `b60 record` once each trip's actual departure is known.
+
+use chrono::{DateTime, Duration, Local};
+use rusqlite::{params, Connection};
+use std::error::Error;
+
+use crate::cli::Options;
+use crate::report::percentile;
+use crate::{record, StopConfig};
+
+/// Lead-time buckets in minutes, each covering `from..to`.
+const LEAD_BUCKETS: [(i64, i64); 7] = [(0, 2), (2, 5), (5, 10), (10, 15), (15, 20), (20, 30), (30, 60)];
+/// Buckets with fewer samples than this are too thin to show a range for.
+const MIN_SAMPLES: usize = 20;
+
+/// Error of predictions made `from_minutes..to_minutes` before the departure,
+/// in seconds (actual minus predicted; positive means the bus left later).
+pub struct LeadError {
+    pub from_minutes: i64,
+    pub to_minutes: i64,
+    pub samples: usize,
+    pub median: Option<i64>,
+    pub p10: Option<i64>,
+    pub p90: Option<i64>,
+    pub mean_abs: Option<i64>,
+}
+
+pub struct Accuracy {
+    pub buckets: Vec<LeadError>,
+}
+
+impl Accuracy {
+    /// The p10..p90 error range for a prediction `lead_secs` ahead of its
+    /// departure, when there is enough history.
+    pub fn range(&self, lead_secs: i64) -> Option<(i64, i64)> {
+        let minutes = lead_secs.max(0) / 60;
+        let bucket = self
+            .buckets
+            .iter()
+            .find(|b| minutes >= b.from_minutes && minutes < b.to_minutes)?;
+        if bucket.samples < MIN_SAMPLES {
+            return None;
+        }
+        Some((bucket.p10?, bucket.p90?))
+    }
+}
+
+/// One departed trip and its predictions as (observed_at, predicted), oldest first.
+struct TripHistory {
+    departed: i64,
+    predictions: Vec<(i64, Option<i64>)>,
+}
+
+impl TripHistory {
+    /// The prediction in effect `lead_secs` before the departure. Predictions are
+    /// only recorded when they change, so this is the latest one observed by then.
+    fn predicted_at(&self, lead_secs: i64) -> Option<i64> {
+        let at = self.departed - lead_secs;
+        self.predictions
+            .iter()
+            .take_while(|(observed_at, _)| *observed_at <= at)
+            .last()
+            .and_then(|(_, predicted)| *predicted)
+    }
+}
+
+fn load_histories(db: &Connection, stop: &StopConfig, since: DateTime<Local>, now: DateTime<Local>) -> Result<Vec<TripHistory>, Box<dyn Error>> {
+    let mut stmt = db.prepare(
+        "SELECT t.service_date, t.trip_id, t.departed, p.observed_at, p.predicted
+         FROM trips t JOIN predictions p USING (service_date, stop_id, trip_id)
+         WHERE t.stop_id = ?1 AND t.route_id = ?2 AND t.departed BETWEEN ?3 AND ?4
+         ORDER BY t.service_date, t.trip_id, p.observed_at",
+    )?;
+    let rows = stmt.query_map(
+        params![stop.stop_id, stop.route_id, since.timestamp(), now.timestamp()],
+        |row| {
+            Ok((
+                (row.get::<_, String>(0)?, row.get::<_, String>(1)?),
+                row.get::<_, i64>(2)?,
+                row.get::<_, i64>(3)?,
+                row.get::<_, Option<i64>>(4)?,
+            ))
+        },
+    )?;
+
+    let mut histories: Vec<TripHistory> = Vec::new();
+    let mut last_key = None;
+    for row in rows {
+        let (key, departed, observed_at, predicted) = row?;
+        if last_key.as_ref() != Some(&key) {
+            histories.push(TripHistory { departed, predictions: Vec::new() });
+            last_key = Some(key);
+        }
+        if let Some(history) = histories.last_mut() {
+            history.predictions.push((observed_at, predicted));
+        }
+    }
+    Ok(histories)
+}
+
+/// Samples every departed trip's prediction once per minute of lead time and
+/// summarizes the errors per lead-time bucket.
+pub fn load(db: &Connection, stop: &StopConfig, since: DateTime<Local>, now: DateTime<Local>) -> Result<Accuracy, Box<dyn Error>> {
+    let histories = load_histories(db, stop, since, now)?;
+
+    let buckets = LEAD_BUCKETS
+        .iter()
+        .map(|&(from_minutes, to_minutes)| {
+            let errors: Vec<i64> = histories
+                .iter()
+                .flat_map(|h| {
+                    (from_minutes..to_minutes).filter_map(move |lead| {
+                        h.predicted_at(lead * 60).map(|predicted| h.departed - predicted)
+                    })
+                })
+                .collect();
+            let mean_abs = (!errors.is_empty())
+                .then(|| errors.iter().map(|e| e.abs()).sum::<i64>() / errors.len() as i64);
+            LeadError {
+                from_minutes,
+                to_minutes,
+                samples: errors.len(),
+                median: percentile(errors.clone(), 0.5),
+                p10: percentile(errors.clone(), 0.1),
+                p90: percentile(errors, 0.9),
+                mean_abs,
+            }
+        })
+        .collect();
+
+    Ok(Accuracy { buckets })
+}
+
+/// Accuracy for each of `stops` over the last `--since` period, for `--confidence`.
+pub fn load_for_stops(stops: &[StopConfig], options: &Options) -> Result<Vec<Accuracy>, Box<dyn Error>> {
+    let db = record::open_db_read_only(options)?;
+    let now = Local::now();
+    let since = now - Duration::minutes(options.since_minutes);
+    stops.iter().map(|stop| load(&db, stop, since, now)).collect()
+}
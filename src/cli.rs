@@ -0,0 +1,498 @@
+use chrono::{DateTime, Local, NaiveDateTime, NaiveTime, TimeZone};
+
+use crate::WindowSettings;
+
+/// Polling interval of `notify`, `record`, `publish` and `serve` when `--watch` is not given.
+pub const DEFAULT_INTERVAL_SECS: u64 = 30;
+
+pub const USAGE: &str = "\
+Usage: b60 [COMMAND] [OPTIONS]
+
+Commands:
+  serve                Serve a calendar feed at /departures.ics and Prometheus metrics at /metrics
+  notify               Watch predictions and alert when it is time to leave for a stop
+  plan [JOURNEY]       List the next itineraries for a configured journey (default: all)
+  transfers [JOURNEY]  Check whether incoming trips make their connections at each transfer
+  publish              Push departure snapshots to webhooks and MQTT on every refresh
+  record               Store schedules, predictions and vehicle positions in a SQLite history
+  report               Summarize recorded on-time performance, delays and headways per stop
+
+Options:
+  --trips <N>          Number of trips shown per stop (default: 3)
+  --horizon <MINUTES>  Only show trips within the next MINUTES (default: unlimited)
+  --past <MINUTES>     Keep trips that left up to MINUTES ago (default: 5)
+  --lookback <MINUTES> How far back schedules are fetched to catch delayed trips (default: 30)
+  --format <FORMAT>    Output format: grid, json, status, waybar, i3bar or ics (default: grid);
+                       `report` takes grid, json or csv
+  --stop <ID>          Only show this stop; may be repeated (default: all monitored stops)
+  --watch <INTERVAL>   Refresh and re-emit every INTERVAL (e.g. \"30s\", \"1m\")
+  --color <WHEN>       Colorize output: auto, always or never (default: auto)
+  --layout <LAYOUT>    Stop layout: auto, grid, list or compact (default: auto)
+  --listen <ADDR>      Address for `serve` to listen on (default: 127.0.0.1:8060)
+  --leave              Show when to leave for each trip and hide trips you can no longer reach
+  --walk <MINUTES>     Walking time to the stops, overriding each stop's configured walk time
+  --accessible         Hide trips that are not wheelchair accessible
+  --bunch <MINUTES>    Flag buses less than MINUTES apart as bunched (default: 3)
+  --confidence         Show the typical error range of live times, from the recorded history
+  --gtfs <ZIP>         Read schedules from this GTFS feed instead of the API; live
+                       predictions still come from the API
+  --gtfs-rt <SOURCE>   Read predictions from this GTFS-realtime feed (URL or .pb file)
+                       instead of the API; may be repeated (needs --gtfs)
+  --provider <NAME=SPEC>
+                       Define a provider stops can name: mbta, gtfs:ZIP[,FEED...] or
+                       fixtures:FILE (a --format json snapshot); may be repeated
+  --record <DIR>       Save every API and GTFS-realtime request and response to DIR
+  --replay <DIR>       Answer API requests from a --record directory instead, with the
+                       clock frozen at each recorded refresh
+  --at <TIME>          Show departures as of TIME (\"YYYY-MM-DD HH:MM\" or \"HH:MM\" today)
+                       from schedules only; with --replay, start at the last refresh
+                       recorded before TIME
+  -h, --help           Print this help
+
+Notify options:
+  --before <MINUTES>   Alert this long before you need to leave (default: 0)
+  --via <CHANNELS>     Comma-separated: desktop, bell, command (default: desktop,bell)
+  --exec <COMMAND>     Shell command run on each alert (implies --via command); receives
+                       B60_KIND, B60_TITLE, B60_MESSAGE, B60_STOP_ID, B60_TRIP_ID,
+                       B60_DEPARTURE, B60_LEAVE_IN
+  --once               Exit after the first leave alert
+
+Publish options:
+  --webhook <URL>      POST each stop's snapshot as JSON to URL; may be repeated
+  --mqtt <HOST[:PORT]> Publish retained snapshots to this MQTT broker (default port: 1883)
+  --topic <TEMPLATE>   MQTT topic; {route}, {stop} and {direction} are expanded
+                       (default: b60/{route}/{stop}/{direction})
+  --ha-discovery       Announce each stop as a Home Assistant sensor
+  --on-change          Only publish a stop when its departures changed
+
+Record and report options:
+  --db <PATH>          History database (default: $XDG_DATA_HOME/b60/history.db)
+  --since <DURATION>   History used by `report` and --confidence (default: 30d)
+  --accuracy           Report prediction error by lead time instead of schedule adherence
+
+Plan options:
+  --min-slack <MINUTES>
+                       Flag transfers with less slack than this as at risk (default: 2)
+
+Durations accept plain minutes (\"45\"), or a unit suffix (\"45m\", \"1h\", \"30d\").
+Per-stop values set in the stop configuration take precedence over these flags.
+With --layout=auto, the grid reflows to the terminal width and becomes a list on narrow screens.
+With --color=auto, colors are used only on a terminal and when NO_COLOR is unset.";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    Grid,
+    Json,
+    /// `report` only: one line per statistics bucket
+    Csv,
+    /// One plain line for tmux, i3blocks or polybar
+    Status,
+    Waybar,
+    I3bar,
+    /// iCalendar with one event per fetched trip
+    Ics,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Command {
+    /// Print departures (the default)
+    Show,
+    Serve,
+    Notify,
+    Plan,
+    Transfers,
+    Publish,
+    Record,
+    Report,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NotifyChannel {
+    /// notify-send (freedesktop notifications over D-Bus)
+    Desktop,
+    /// Terminal bell
+    Bell,
+    /// The `--exec` command hook
+    Command,
+}
+
+/// Where a `--provider NAME=SPEC` gets its departures.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProviderSpec {
+    /// `mbta`: the MBTA V3 API
+    Mbta,
+    /// `gtfs:ZIP[,FEED...]`: a GTFS feed, live with GTFS-realtime feeds
+    Gtfs { zip: String, realtime: Vec<String> },
+    /// `fixtures:FILE`: a snapshot written by `--format json`
+    Fixtures(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    /// Whether to emit colors on a stream, honoring `NO_COLOR` (https://no-color.org) in auto mode.
+    pub fn enabled(self, is_terminal: bool) -> bool {
+        match self {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto => {
+                is_terminal && std::env::var_os("NO_COLOR").is_none_or(|v| v.is_empty())
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Layout {
+    Auto,
+    Grid,
+    List,
+    Compact,
+}
+
+pub struct Options {
+    pub command: Command,
+    pub window: WindowSettings,
+    pub format: OutputFormat,
+    pub color: ColorChoice,
+    pub layout: Layout,
+    pub stops: Vec<String>,
+    pub watch: Option<std::time::Duration>,
+    pub listen: String,
+    pub walk_minutes: Option<i64>,
+    pub notify_before_minutes: i64,
+    pub notify_via: Vec<NotifyChannel>,
+    pub notify_exec: Option<String>,
+    pub once: bool,
+    pub leave: bool,
+    /// Journey name for `plan` and `transfers`
+    pub journey: Option<String>,
+    /// Transfers with less slack than this are flagged as at risk
+    pub min_slack_minutes: i64,
+    pub webhooks: Vec<String>,
+    /// MQTT broker as HOST[:PORT]
+    pub mqtt: Option<String>,
+    pub mqtt_topic: String,
+    pub ha_discovery: bool,
+    pub on_change: bool,
+    /// History database for `record` and `report`
+    pub db: Option<String>,
+    /// History used by `report` and `--confidence`
+    pub since_minutes: i64,
+    pub accuracy: bool,
+    pub confidence: bool,
+    /// Buses closer together than this are flagged as bunched
+    pub bunch_minutes: i64,
+    /// GTFS static zip used for schedules instead of the API
+    pub gtfs: Option<String>,
+    /// GTFS-realtime feeds (URLs or files) used for predictions instead of the API
+    pub gtfs_rt: Vec<String>,
+    /// Named providers that stops can point at, in addition to `mbta`
+    pub providers: Vec<(String, ProviderSpec)>,
+    /// Directory MBTA API exchanges are saved to
+    pub record_dir: Option<String>,
+    /// Directory of saved exchanges served instead of the API
+    pub replay_dir: Option<String>,
+    /// Simulated time departures are shown for
+    pub at: Option<DateTime<Local>>,
+}
+
+/// Parses command-line arguments (excluding the program name).
+pub fn parse_args<I: IntoIterator<Item = String>>(args: I) -> Result<Options, String> {
+    let mut options = Options {
+        command: Command::Show,
+        window: WindowSettings::default(),
+        format: OutputFormat::Grid,
+        color: ColorChoice::Auto,
+        layout: Layout::Auto,
+        stops: Vec::new(),
+        watch: None,
+        listen: "127.0.0.1:8060".to_string(),
+        walk_minutes: None,
+        notify_before_minutes: 0,
+        notify_via: vec![NotifyChannel::Desktop, NotifyChannel::Bell],
+        notify_exec: None,
+        once: false,
+        leave: false,
+        journey: None,
+        min_slack_minutes: 2,
+        webhooks: Vec::new(),
+        mqtt: None,
+        mqtt_topic: "b60/{route}/{stop}/{direction}".to_string(),
+        ha_discovery: false,
+        on_change: false,
+        db: None,
+        since_minutes: 30 * 24 * 60,
+        accuracy: false,
+        confidence: false,
+        bunch_minutes: 3,
+        gtfs: None,
+        gtfs_rt: Vec::new(),
+        providers: Vec::new(),
+        record_dir: None,
+        replay_dir: None,
+        at: None,
+    };
+
+    let mut args = args.into_iter().peekable();
+    match args.peek().map(|a| a.as_str()) {
+        Some("serve") => options.command = Command::Serve,
+        Some("notify") => options.command = Command::Notify,
+        Some("plan") => options.command = Command::Plan,
+        Some("transfers") => options.command = Command::Transfers,
+        Some("publish") => options.command = Command::Publish,
+        Some("record") => options.command = Command::Record,
+        Some("report") => options.command = Command::Report,
+        _ => {}
+    }
+    if options.command != Command::Show {
+        args.next();
+    }
+    while let Some(arg) = args.next() {
+        // Accept both `--flag value` and `--flag=value`
+        let (flag, inline_value) = match arg.split_once('=') {
+            Some((flag, value)) if flag.starts_with("--") => (flag.to_string(), Some(value.to_string())),
+            _ => (arg.clone(), None),
+        };
+        let mut value = || {
+            inline_value
+                .clone()
+                .or_else(|| args.next())
+                .ok_or_else(|| format!("missing value for {}", flag))
+        };
+
+        match flag.as_str() {
+            "--trips" => {
+                let v = value()?;
+                options.window.trips = v
+                    .parse()
+                    .map_err(|_| format!("invalid trip count: {}", v))?;
+            }
+            "--horizon" => options.window.horizon_minutes = Some(parse_minutes(&value()?)?),
+            "--past" => options.window.past_cutoff_minutes = parse_minutes(&value()?)?,
+            "--lookback" => options.window.lookback_minutes = parse_minutes(&value()?)?,
+            "--format" => {
+                options.format = match value()?.as_str() {
+                    "grid" => OutputFormat::Grid,
+                    "json" => OutputFormat::Json,
+                    "csv" => OutputFormat::Csv,
+                    "status" => OutputFormat::Status,
+                    "waybar" => OutputFormat::Waybar,
+                    "i3bar" => OutputFormat::I3bar,
+                    "ics" => OutputFormat::Ics,
+                    other => return Err(format!("unknown format: {}", other)),
+                }
+            }
+            "--color" => {
+                options.color = match value()?.as_str() {
+                    "auto" => ColorChoice::Auto,
+                    "always" => ColorChoice::Always,
+                    "never" => ColorChoice::Never,
+                    other => return Err(format!("unknown color choice: {}", other)),
+                }
+            }
+            "--layout" => {
+                options.layout = match value()?.as_str() {
+                    "auto" => Layout::Auto,
+                    "grid" => Layout::Grid,
+                    "list" => Layout::List,
+                    "compact" => Layout::Compact,
+                    other => return Err(format!("unknown layout: {}", other)),
+                }
+            }
+            "--stop" => options.stops.push(value()?),
+            "--watch" => {
+                let secs = parse_seconds(&value()?)?;
+                if secs == 0 {
+                    return Err("--watch interval must be positive".to_string());
+                }
+                options.watch = Some(std::time::Duration::from_secs(secs));
+            }
+            "--listen" => options.listen = value()?,
+            "--walk" => options.walk_minutes = Some(parse_minutes(&value()?)?),
+            "--before" => options.notify_before_minutes = parse_minutes(&value()?)?,
+            "--via" => {
+                options.notify_via = value()?
+                    .split(',')
+                    .map(|c| match c.trim() {
+                        "desktop" => Ok(NotifyChannel::Desktop),
+                        "bell" => Ok(NotifyChannel::Bell),
+                        "command" => Ok(NotifyChannel::Command),
+                        other => Err(format!("unknown notification channel: {}", other)),
+                    })
+                    .collect::<Result<_, _>>()?;
+            }
+            "--exec" => {
+                options.notify_exec = Some(value()?);
+                if !options.notify_via.contains(&NotifyChannel::Command) {
+                    options.notify_via.push(NotifyChannel::Command);
+                }
+            }
+            "--once" => options.once = true,
+            "--leave" => options.leave = true,
+            "--webhook" => options.webhooks.push(value()?),
+            "--mqtt" => options.mqtt = Some(value()?),
+            "--topic" => options.mqtt_topic = value()?,
+            "--ha-discovery" => options.ha_discovery = true,
+            "--on-change" => options.on_change = true,
+            "--gtfs" => options.gtfs = Some(value()?),
+            "--gtfs-rt" => options.gtfs_rt.push(value()?),
+            "--provider" => options.providers.push(parse_provider(&value()?)?),
+            "--record" => options.record_dir = Some(value()?),
+            "--replay" => options.replay_dir = Some(value()?),
+            "--at" => options.at = Some(parse_at(&value()?)?),
+            "--db" => options.db = Some(value()?),
+            "--since" => options.since_minutes = parse_minutes(&value()?)?,
+            "--accuracy" => options.accuracy = true,
+            "--confidence" => options.confidence = true,
+            "--accessible" => options.window.accessible_only = true,
+            "--bunch" => options.bunch_minutes = parse_minutes(&value()?)?,
+            "--min-slack" => options.min_slack_minutes = parse_minutes(&value()?)?,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                std::process::exit(0);
+            }
+            _ if matches!(options.command, Command::Plan | Command::Transfers)
+                && options.journey.is_none()
+                && !arg.starts_with('-') =>
+            {
+                options.journey = Some(arg.clone());
+            }
+            _ => return Err(format!("unknown argument: {}", arg)),
+        }
+    }
+
+    if options.record_dir.is_some() && options.replay_dir.is_some() {
+        return Err("--record and --replay cannot be combined".to_string());
+    }
+    if options.at.is_some() && (options.record_dir.is_some() || options.command == Command::Record) {
+        return Err("--at cannot be combined with recording".to_string());
+    }
+    if options.command == Command::Notify && options.stops.is_empty() {
+        return Err("notify needs at least one --stop".to_string());
+    }
+    if options.command == Command::Publish && options.webhooks.is_empty() && options.mqtt.is_none() {
+        return Err("publish needs a --webhook or --mqtt target".to_string());
+    }
+    match (options.command, options.format) {
+        (Command::Report, OutputFormat::Grid | OutputFormat::Json | OutputFormat::Csv) => {}
+        (Command::Report, _) => return Err("report supports --format grid, json or csv".to_string()),
+        (_, OutputFormat::Csv) => return Err("--format csv is only supported by report".to_string()),
+        _ => {}
+    }
+    if options.ha_discovery && options.mqtt.is_none() {
+        return Err("--ha-discovery needs --mqtt".to_string());
+    }
+    // Updates name platforms; only the static feed maps them to stations and stop positions
+    if !options.gtfs_rt.is_empty() && options.gtfs.is_none() {
+        return Err("--gtfs-rt needs --gtfs".to_string());
+    }
+
+    Ok(options)
+}
+
+/// Parses NAME=SPEC, where SPEC is "mbta", "gtfs:ZIP[,FEED...]" or "fixtures:FILE".
+fn parse_provider(s: &str) -> Result<(String, ProviderSpec), String> {
+    let (name, spec) = s
+        .split_once('=')
+        .filter(|(name, _)| !name.is_empty())
+        .ok_or_else(|| format!("invalid provider (expected NAME=SPEC): {}", s))?;
+    let spec = match spec.split_once(':') {
+        _ if spec == "mbta" => ProviderSpec::Mbta,
+        Some(("gtfs", sources)) => {
+            let mut sources = sources.split(',').map(str::to_string);
+            let zip = sources.next().filter(|z| !z.is_empty()).ok_or("gtfs provider needs a zip file")?;
+            ProviderSpec::Gtfs { zip, realtime: sources.collect() }
+        }
+        Some(("fixtures", path)) if !path.is_empty() => ProviderSpec::Fixtures(path.to_string()),
+        _ => return Err(format!("unknown provider spec: {}", spec)),
+    };
+    Ok((name.to_string(), spec))
+}
+
+/// Parses a local time, "YYYY-MM-DD HH:MM" or "HH:MM" for today. When the clocks
+/// go back, an ambiguous time is the first of the two.
+fn parse_at(s: &str) -> Result<DateTime<Local>, String> {
+    let naive = NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M")
+        .or_else(|_| NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M"))
+        .or_else(|_| NaiveTime::parse_from_str(s, "%H:%M").map(|t| Local::now().date_naive().and_time(t)))
+        .map_err(|_| format!("invalid time: {} (expected \"YYYY-MM-DD HH:MM\" or \"HH:MM\")", s))?;
+    Local
+        .from_local_datetime(&naive)
+        .earliest()
+        .ok_or_else(|| format!("{} does not exist: the clocks go forward then", s))
+}
+
+/// Longest duration accepted, in minutes (ten years), so that times computed
+/// from it stay within range.
+const MAX_DURATION_MINUTES: i64 = 10 * 366 * 24 * 60;
+/// Longest interval accepted, in seconds (a week).
+const MAX_INTERVAL_SECS: u64 = 7 * 24 * 3600;
+
+/// Parses a duration in minutes: "45", "45m", "1h" or "30d".
+pub fn parse_minutes(s: &str) -> Result<i64, String> {
+    let (digits, scale) = if let Some(d) = s.strip_suffix('d') {
+        (d, 24 * 60)
+    } else if let Some(h) = s.strip_suffix('h') {
+        (h, 60)
+    } else if let Some(m) = s.strip_suffix('m') {
+        (m, 1)
+    } else {
+        (s, 1)
+    };
+    match digits.parse::<i64>() {
+        Ok(n) if n < 0 => Err(format!("duration must not be negative: {}", s)),
+        Ok(n) => n
+            .checked_mul(scale)
+            .filter(|minutes| *minutes <= MAX_DURATION_MINUTES)
+            .ok_or_else(|| format!("duration too long: {}", s)),
+        Err(_) => Err(format!("invalid duration: {}", s)),
+    }
+}
+
+/// Parses an interval in seconds: "30", "30s", "2m" or "1h".
+pub fn parse_seconds(s: &str) -> Result<u64, String> {
+    let (digits, scale) = if let Some(h) = s.strip_suffix('h') {
+        (h, 3600)
+    } else if let Some(m) = s.strip_suffix('m') {
+        (m, 60)
+    } else if let Some(secs) = s.strip_suffix('s') {
+        (secs, 1)
+    } else {
+        (s, 1)
+    };
+    let n = digits.parse::<u64>().map_err(|_| format!("invalid interval: {}", s))?;
+    n.checked_mul(scale)
+        .filter(|secs| *secs <= MAX_INTERVAL_SECS)
+        .ok_or_else(|| format!("interval too long: {}", s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_durations_out_of_range() {
+        assert_eq!(parse_minutes("2h"), Ok(120));
+        assert_eq!(parse_minutes("30d"), Ok(30 * 24 * 60));
+        assert!(parse_minutes("-5").is_err());
+        assert!(parse_minutes("999999999999h").is_err());
+        assert!(parse_minutes("99999999999999999d").is_err());
+        assert_eq!(parse_seconds("2m"), Ok(120));
+        assert!(parse_seconds("99999999999999999h").is_err());
+        assert!(parse_seconds("30d").is_err());
+    }
+
+    #[test]
+    fn csv_is_only_accepted_for_report() {
+        let parse = |args: &[&str]| parse_args(args.iter().map(|a| a.to_string()));
+        assert_eq!(parse(&["report", "--format", "csv"]).map(|o| o.format), Ok(OutputFormat::Csv));
+        assert!(parse(&["report", "--format", "waybar"]).is_err());
+        assert!(parse(&["--format", "csv"]).is_err());
+    }
+}
@@ -0,0 +1,467 @@
+//! Offline schedules from a GTFS static feed. The zip given with `--gtfs` is
+//! imported into an indexed SQLite cache, re-imported whenever the file changes,
+//! and queried in place of the `/schedules` endpoint.
+
+use chrono::{DateTime, Datelike, Duration, Local, NaiveDate, TimeZone};
+use rusqlite::{params, Connection, Transaction};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use crate::{mbta, record, wheelchair_accessible, ScheduledTrip, StopConfig, WindowSettings, SCHEDULE_WINDOW_MINUTES};
+
+/// Bumped whenever `SCHEMA` changes; caches of another version are rebuilt.
+const CACHE_VERSION: i64 = 2;
+
+/// Times in `stop_times` are seconds after the service day's noon minus 12h, and
+/// may exceed 24h for trips running past midnight. `calendar.days` is a
+/// Monday-first mask of '0'/'1'; dates are YYYYMMDD as in the feed.
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS meta (
+    key   TEXT PRIMARY KEY,
+    value TEXT NOT NULL
+) WITHOUT ROWID;
+
+CREATE TABLE IF NOT EXISTS stops (
+    stop_id        TEXT PRIMARY KEY,
+    stop_name      TEXT,
+    parent_station TEXT
+) WITHOUT ROWID;
+CREATE INDEX IF NOT EXISTS stops_by_parent ON stops (parent_station);
+
+CREATE TABLE IF NOT EXISTS trips (
+    trip_id       TEXT PRIMARY KEY,
+    route_id      TEXT NOT NULL,
+    service_id    TEXT NOT NULL,
+    direction_id  INTEGER,
+    trip_headsign TEXT,
+    wheelchair_accessible INTEGER
+) WITHOUT ROWID;
+
+CREATE TABLE IF NOT EXISTS stop_times (
+    trip_id        TEXT NOT NULL,
+    stop_id        TEXT NOT NULL,
+    stop_sequence  INTEGER NOT NULL,
+    arrival_secs   INTEGER,
+    departure_secs INTEGER,
+    PRIMARY KEY (trip_id, stop_sequence)
+) WITHOUT ROWID;
+CREATE INDEX IF NOT EXISTS stop_times_by_stop ON stop_times (stop_id, departure_secs);
+
+CREATE TABLE IF NOT EXISTS calendar (
+    service_id TEXT PRIMARY KEY,
+    days       TEXT NOT NULL,
+    start_date TEXT NOT NULL,
+    end_date   TEXT NOT NULL
+) WITHOUT ROWID;
+
+CREATE TABLE IF NOT EXISTS calendar_dates (
+    service_id     TEXT NOT NULL,
+    date           TEXT NOT NULL,
+    exception_type INTEGER NOT NULL,
+    PRIMARY KEY (service_id, date)
+) WITHOUT ROWID;
+";
+
+/// Service ids active on ?1 (YYYYMMDD), whose weekday is character ?2 of `days`:
+/// calendar.txt patterns plus the dates added and minus those removed in
+/// calendar_dates.txt.
+const ACTIVE_SERVICES: &str = "
+SELECT service_id FROM calendar
+ WHERE substr(days, ?2, 1) = '1' AND ?1 BETWEEN start_date AND end_date
+UNION
+SELECT service_id FROM calendar_dates WHERE date = ?1 AND exception_type = 1
+EXCEPT
+SELECT service_id FROM calendar_dates WHERE date = ?1 AND exception_type = 2
+";
+
+pub struct GtfsStore {
+    db: Connection,
+    /// Dates already warned about having no service
+    no_service_warned: RefCell<HashSet<NaiveDate>>,
+}
+
+/// Column positions of one GTFS file, looked up by header name since feeds
+/// order (and omit) optional columns freely.
+struct Columns(HashMap<String, usize>);
+
+impl Columns {
+    fn new(headers: &csv::StringRecord) -> Columns {
+        Columns(headers.iter().enumerate().map(|(i, h)| (h.trim().to_string(), i)).collect())
+    }
+
+    fn get<'r>(&self, record: &'r csv::StringRecord, name: &str) -> Option<&'r str> {
+        self.0.get(name).and_then(|&i| record.get(i)).map(str::trim).filter(|v| !v.is_empty())
+    }
+
+}
+
+/// Parses a GTFS time ("7:05:00" or "25:10:00") into seconds since the service day began.
+fn parse_gtfs_time(s: &str) -> Option<i64> {
+    let mut parts = s.split(':').map(|p| p.parse::<i64>().ok());
+    let (h, m, sec) = (parts.next()??, parts.next()??, parts.next()??);
+    Some(h * 3600 + m * 60 + sec)
+}
+
+/// The instant GTFS times on `date` count from in `tz`: noon minus 12h, which is
+/// midnight except on days when the clocks change.
+pub fn service_day_start<Tz: TimeZone>(date: NaiveDate, tz: &Tz) -> Option<DateTime<Tz>> {
+    let noon = tz.from_local_datetime(&date.and_hms_opt(12, 0, 0)?).earliest()?;
+    Some(noon - Duration::hours(12))
+}
+
+/// Identifies a zip by path, size and modification time to detect a new download.
+fn fingerprint(path: &Path) -> Result<String, Box<dyn Error>> {
+    let meta = std::fs::metadata(path)?;
+    let modified = meta
+        .modified()?
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    Ok(format!("{}:{}:{}", std::fs::canonicalize(path)?.display(), meta.len(), modified))
+}
+
+/// FNV-1a hash of `bytes`, stable across builds unlike `DefaultHasher`.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, b| (hash ^ *b as u64).wrapping_mul(0x0100_0000_01b3))
+}
+
+/// The cache for `zip_path`: named after the zip and a hash of its canonical
+/// path, so same-named feeds in different directories get their own cache.
+fn cache_path(zip_path: &Path) -> Result<PathBuf, Box<dyn Error>> {
+    let canonical = std::fs::canonicalize(zip_path)?;
+    let stem = zip_path.file_stem().map(|s| s.to_string_lossy()).unwrap_or_default();
+    let hash = fnv1a(canonical.as_os_str().as_encoded_bytes());
+    Ok(record::data_dir().join(format!("gtfs-{}-{:016x}.db", stem, hash)))
+}
+
+/// Streams the rows of `name` from the zip into `insert`. A missing file is an
+/// error only when `required`, and so is a file lacking any of `columns`.
+fn import_file(
+    archive: &mut zip::ZipArchive<File>,
+    name: &str,
+    required: bool,
+    columns: &[&str],
+    mut insert: impl FnMut(&Columns, &csv::StringRecord) -> Result<(), Box<dyn Error>>,
+) -> Result<(), Box<dyn Error>> {
+    let file = match archive.by_name(name) {
+        Ok(file) => file,
+        Err(zip::result::ZipError::FileNotFound) if !required => return Ok(()),
+        Err(e) => return Err(format!("{}: {}", name, e).into()),
+    };
+    let mut reader = csv::ReaderBuilder::new().flexible(true).from_reader(file);
+    let found = Columns::new(reader.headers()?);
+    if let Some(missing) = columns.iter().find(|c| !found.0.contains_key(**c)) {
+        return Err(format!("{} has no {} column", name, missing).into());
+    }
+    for record in reader.records() {
+        insert(&found, &record?)?;
+    }
+    Ok(())
+}
+
+fn import(tx: &Transaction, zip_path: &Path) -> Result<(), Box<dyn Error>> {
+    let mut archive = zip::ZipArchive::new(File::open(zip_path)?)?;
+    for table in ["stops", "trips", "stop_times", "calendar", "calendar_dates"] {
+        tx.execute(&format!("DELETE FROM {}", table), [])?;
+    }
+
+    let mut stmt = tx.prepare("INSERT OR REPLACE INTO stops VALUES (?1, ?2, ?3)")?;
+    import_file(&mut archive, "stops.txt", true, &["stop_id"], |c, r| {
+        stmt.execute(params![c.get(r, "stop_id"), c.get(r, "stop_name"), c.get(r, "parent_station")])?;
+        Ok(())
+    })?;
+
+    let mut stmt = tx.prepare("INSERT OR REPLACE INTO trips VALUES (?1, ?2, ?3, ?4, ?5, ?6)")?;
+    import_file(&mut archive, "trips.txt", true, &["trip_id", "route_id", "service_id"], |c, r| {
+        stmt.execute(params![
+            c.get(r, "trip_id"),
+            c.get(r, "route_id"),
+            c.get(r, "service_id"),
+            c.get(r, "direction_id").and_then(|d| d.parse::<i32>().ok()),
+            c.get(r, "trip_headsign"),
+            c.get(r, "wheelchair_accessible").and_then(|w| w.parse::<i64>().ok()),
+        ])?;
+        Ok(())
+    })?;
+
+    let mut stmt = tx.prepare("INSERT OR REPLACE INTO stop_times VALUES (?1, ?2, ?3, ?4, ?5)")?;
+    import_file(&mut archive, "stop_times.txt", true, &["trip_id", "stop_id", "stop_sequence"], |c, r| {
+        stmt.execute(params![
+            c.get(r, "trip_id"),
+            c.get(r, "stop_id"),
+            c.get(r, "stop_sequence").and_then(|s| s.parse::<i64>().ok()),
+            c.get(r, "arrival_time").and_then(parse_gtfs_time),
+            c.get(r, "departure_time").and_then(parse_gtfs_time),
+        ])?;
+        Ok(())
+    })?;
+
+    let mut stmt = tx.prepare("INSERT OR REPLACE INTO calendar VALUES (?1, ?2, ?3, ?4)")?;
+    import_file(&mut archive, "calendar.txt", false, &["service_id", "start_date", "end_date"], |c, r| {
+        let days: String = ["monday", "tuesday", "wednesday", "thursday", "friday", "saturday", "sunday"]
+            .iter()
+            .map(|day| if c.get(r, day) == Some("1") { '1' } else { '0' })
+            .collect();
+        stmt.execute(params![c.get(r, "service_id"), days, c.get(r, "start_date"), c.get(r, "end_date")])?;
+        Ok(())
+    })?;
+
+    let mut stmt = tx.prepare("INSERT OR REPLACE INTO calendar_dates VALUES (?1, ?2, ?3)")?;
+    import_file(&mut archive, "calendar_dates.txt", false, &["service_id", "date", "exception_type"], |c, r| {
+        stmt.execute(params![
+            c.get(r, "service_id"),
+            c.get(r, "date"),
+            c.get(r, "exception_type").and_then(|t| t.parse::<i32>().ok()),
+        ])?;
+        Ok(())
+    })?;
+
+    Ok(())
+}
+
+impl GtfsStore {
+    /// Opens the cache for `zip_path` in the data directory, importing the feed
+    /// first if it has not been imported or the zip changed since.
+    pub fn open(zip_path: &str) -> Result<GtfsStore, Box<dyn Error>> {
+        let zip_path = Path::new(zip_path);
+        let fingerprint = fingerprint(zip_path).map_err(|e| format!("{}: {}", zip_path.display(), e))?;
+
+        // One cache per feed, so several agencies' feeds don't evict each other
+        std::fs::create_dir_all(record::data_dir())?;
+        let mut db = Connection::open(cache_path(zip_path)?)?;
+        let version: i64 = db.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        if version != CACHE_VERSION {
+            for table in ["meta", "stops", "trips", "stop_times", "calendar", "calendar_dates"] {
+                db.execute(&format!("DROP TABLE IF EXISTS {}", table), [])?;
+            }
+            db.pragma_update(None, "user_version", CACHE_VERSION)?;
+        }
+        db.execute_batch(SCHEMA)?;
+
+        let imported: Option<String> = db
+            .query_row("SELECT value FROM meta WHERE key = 'source'", [], |row| row.get(0))
+            .ok();
+        if imported.as_deref() != Some(fingerprint.as_str()) {
+            eprintln!("Importing GTFS feed {}...", zip_path.display());
+            let tx = db.transaction()?;
+            import(&tx, zip_path).map_err(|e| format!("{}: {}", zip_path.display(), e))?;
+            tx.execute("INSERT OR REPLACE INTO meta VALUES ('source', ?1)", params![fingerprint])?;
+            tx.commit()?;
+        }
+
+        Ok(GtfsStore { db, no_service_warned: RefCell::new(HashSet::new()) })
+    }
+
+    /// Service ids running on `date`.
+    fn active_services(&self, date: NaiveDate) -> Result<Vec<String>, Box<dyn Error>> {
+        let mut stmt = self.db.prepare_cached(ACTIVE_SERVICES)?;
+        let ids = stmt.query_map(
+            params![date.format("%Y%m%d").to_string(), date.weekday().num_days_from_monday() + 1],
+            |row| row.get(0),
+        )?;
+        Ok(ids.collect::<Result<_, _>>()?)
+    }
+
+    /// Warns once per date when the feed runs no service at all on `date`,
+    /// which usually means its calendar has expired.
+    fn warn_if_no_service(&self, date: NaiveDate) -> Result<(), Box<dyn Error>> {
+        if !self.no_service_warned.borrow().contains(&date) && self.active_services(date)?.is_empty() {
+            eprintln!("⚠️  The GTFS feed has no service on {}; it may be out of date", date);
+            self.no_service_warned.borrow_mut().insert(date);
+        }
+        Ok(())
+    }
+
+    /// The station `stop_id` is a platform of, if any.
+    pub fn parent_station(&self, stop_id: &str) -> Option<String> {
+        self.db
+            .query_row("SELECT parent_station FROM stops WHERE stop_id = ?1", params![stop_id], |row| row.get(0))
+            .ok()
+            .flatten()
+    }
+
+    /// How many of `trip_id`'s stops come after stop sequence `from` up to and
+    /// including `to`. Sequence numbers need not be consecutive (MBTA numbers
+    /// stops 1, 10, 20, ...), so positions are counted. `None` for an unknown trip.
+    pub fn stops_between(&self, trip_id: &str, from: u32, to: u32) -> Option<i32> {
+        let count: i32 = self
+            .db
+            .prepare_cached(
+                "SELECT count(*) FROM stop_times WHERE trip_id = ?1 AND stop_sequence > ?2 AND stop_sequence <= ?3",
+            )
+            .and_then(|mut stmt| stmt.query_row(params![trip_id, from, to], |row| row.get(0)))
+            .ok()?;
+        (count > 0).then_some(count)
+    }
+
+    /// Scheduled trips at `stop` within the same window `/schedules` is queried
+    /// for. Yesterday's service day is included for trips running past midnight,
+    /// and a parent station matches the stop times of its child stops.
+    pub fn schedules(
+        &self,
+        stop: &StopConfig,
+        window: WindowSettings,
+        now: DateTime<Local>,
+    ) -> Result<Vec<ScheduledTrip>, Box<dyn Error>> {
+        let start = now - Duration::minutes(window.lookback_minutes);
+        let end = now + Duration::minutes(window.horizon_minutes.unwrap_or(SCHEDULE_WINDOW_MINUTES));
+        self.warn_if_no_service(mbta::service_date(now))?;
+
+        let mut stmt = self.db.prepare_cached(&format!(
+            "SELECT st.trip_id, t.trip_headsign, st.arrival_secs, st.departure_secs, t.wheelchair_accessible
+             FROM stop_times st JOIN trips t USING (trip_id)
+             WHERE st.stop_id IN (SELECT stop_id FROM stops WHERE stop_id = ?3 OR parent_station = ?3)
+               AND t.route_id = ?4 AND t.direction_id = ?5
+               AND t.service_id IN ({})
+               AND (st.departure_secs BETWEEN ?6 AND ?7 OR st.arrival_secs BETWEEN ?6 AND ?7)
+             ORDER BY coalesce(st.arrival_secs, st.departure_secs)",
+            ACTIVE_SERVICES
+        ))?;
+
+        let mut trips = Vec::new();
+        let mut date = start.date_naive() - Duration::days(1);
+        while date <= end.date_naive() {
+            let Some(day_start) = service_day_start(date, &Local) else {
+                date += Duration::days(1);
+                continue;
+            };
+            let at = |secs: Option<i64>| secs.map(|s| day_start + Duration::seconds(s));
+            let rows = stmt.query_map(
+                params![
+                    date.format("%Y%m%d").to_string(),
+                    date.weekday().num_days_from_monday() + 1,
+                    stop.stop_id,
+                    stop.route_id,
+                    stop.direction_id,
+                    (start - day_start).num_seconds(),
+                    (end - day_start).num_seconds(),
+                ],
+                |row| {
+                    Ok(ScheduledTrip {
+                        trip_id: row.get(0)?,
+                        headsign: row.get(1)?,
+                        arrival: at(row.get(2)?),
+                        departure: at(row.get(3)?),
+                        wheelchair_accessible: row.get::<_, Option<i64>>(4)?.and_then(wheelchair_accessible),
+                    })
+                },
+            )?;
+            for trip in rows {
+                trips.push(trip?);
+            }
+            date += Duration::days(1);
+        }
+        Ok(trips)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{FixedOffset, LocalResult, NaiveDateTime};
+
+    /// A feed with weekday and Sunday service in October 2026, replaced by a
+    /// holiday service on Monday 12 October, and one trip numbering its stops
+    /// 1, 10, 20, 30.
+    fn store() -> GtfsStore {
+        let db = Connection::open_in_memory().unwrap();
+        db.execute_batch(SCHEMA).unwrap();
+        db.execute_batch(
+            "INSERT INTO calendar VALUES ('WKDY', '1111100', '20261001', '20261031');
+             INSERT INTO calendar VALUES ('SUN', '0000001', '20261001', '20261031');
+             INSERT INTO calendar_dates VALUES ('HOLIDAY', '20261012', 1);
+             INSERT INTO calendar_dates VALUES ('WKDY', '20261012', 2);
+             INSERT INTO stop_times VALUES ('T1', 'a', 1, NULL, 0), ('T1', 'b', 10, 60, 60),
+                                           ('T1', 'c', 20, 120, 120), ('T1', 'd', 30, 180, NULL);",
+        )
+        .unwrap();
+        GtfsStore { db, no_service_warned: RefCell::new(HashSet::new()) }
+    }
+
+    fn date(y: i32, m: u32, d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(y, m, d).unwrap()
+    }
+
+    #[test]
+    fn parses_gtfs_times() {
+        assert_eq!(parse_gtfs_time("7:05:00"), Some(7 * 3600 + 5 * 60));
+        assert_eq!(parse_gtfs_time("25:10:30"), Some(25 * 3600 + 10 * 60 + 30));
+        assert_eq!(parse_gtfs_time("7:05"), None);
+        assert_eq!(parse_gtfs_time("noon"), None);
+    }
+
+    /// US Eastern time in 2026, so daylight saving can be tested without
+    /// depending on the machine's time zone.
+    #[derive(Clone, Copy)]
+    struct Eastern2026;
+
+    impl TimeZone for Eastern2026 {
+        type Offset = FixedOffset;
+
+        fn from_offset(_: &FixedOffset) -> Self {
+            Eastern2026
+        }
+
+        fn offset_from_local_date(&self, local: &NaiveDate) -> LocalResult<FixedOffset> {
+            self.offset_from_local_datetime(&local.and_hms_opt(0, 0, 0).unwrap())
+        }
+
+        fn offset_from_local_datetime(&self, local: &NaiveDateTime) -> LocalResult<FixedOffset> {
+            // EDT first, so a repeated hour resolves to its earlier instant
+            let offsets: Vec<FixedOffset> = [4, 5]
+                .into_iter()
+                .map(|hours| FixedOffset::west_opt(hours * 3600).unwrap())
+                .filter(|offset| self.offset_from_utc_datetime(&(*local - *offset)) == *offset)
+                .collect();
+            match offsets[..] {
+                [offset] => LocalResult::Single(offset),
+                [earlier, later] => LocalResult::Ambiguous(earlier, later),
+                _ => LocalResult::None,
+            }
+        }
+
+        fn offset_from_utc_date(&self, utc: &NaiveDate) -> FixedOffset {
+            self.offset_from_utc_datetime(&utc.and_hms_opt(0, 0, 0).unwrap())
+        }
+
+        fn offset_from_utc_datetime(&self, utc: &NaiveDateTime) -> FixedOffset {
+            let dst = date(2026, 3, 8).and_hms_opt(7, 0, 0).unwrap()..date(2026, 11, 1).and_hms_opt(6, 0, 0).unwrap();
+            let hours = if dst.contains(utc) { 4 } else { 5 };
+            FixedOffset::west_opt(hours * 3600).unwrap()
+        }
+    }
+
+    #[test]
+    fn service_day_starts_at_noon_minus_12h() {
+        let start = |d| service_day_start(d, &Eastern2026).unwrap().naive_local();
+        assert_eq!(start(date(2026, 10, 14)), date(2026, 10, 14).and_hms_opt(0, 0, 0).unwrap());
+        // Clocks spring forward at 2am, so noon is only 11 hours after midnight
+        assert_eq!(start(date(2026, 3, 8)), date(2026, 3, 7).and_hms_opt(23, 0, 0).unwrap());
+        // Clocks fall back at 2am, so noon is 13 hours after midnight
+        assert_eq!(start(date(2026, 11, 1)), date(2026, 11, 1).and_hms_opt(1, 0, 0).unwrap());
+    }
+
+    #[test]
+    fn active_services_apply_calendar_dates() {
+        let store = store();
+        assert_eq!(store.active_services(date(2026, 10, 13)).unwrap(), ["WKDY"]);
+        assert_eq!(store.active_services(date(2026, 10, 18)).unwrap(), ["SUN"]);
+        // Added and removed in calendar_dates.txt
+        assert_eq!(store.active_services(date(2026, 10, 12)).unwrap(), ["HOLIDAY"]);
+        // After calendar.txt has expired
+        assert!(store.active_services(date(2026, 11, 2)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn counts_stops_between_sparse_sequences() {
+        let store = store();
+        assert_eq!(store.stops_between("T1", 1, 20), Some(2));
+        assert_eq!(store.stops_between("T1", 10, 30), Some(2));
+        assert_eq!(store.stops_between("T1", 20, 20), None);
+        assert_eq!(store.stops_between("T2", 1, 20), None);
+    }
+}
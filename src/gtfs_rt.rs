@@ -0,0 +1,242 @@
+//! GTFS-realtime feeds as a source of live predictions. TripUpdates give the
+//! predicted times and VehiclePositions the stops-away count; both are decoded
+//! with the subset of `gtfs-realtime.proto` below, and read from a URL or from a
+//! local `.pb` snapshot.
+
+use chrono::{DateTime, Local, TimeZone};
+use prost::Message;
+use reqwest::Client;
+use std::collections::HashMap;
+use std::error::Error;
+
+use crate::gtfs::GtfsStore;
+use crate::provider::RouteGroup;
+use crate::{metrics, replay, RoutePredictions, StopConfig, StopPrediction};
+
+/// `StopTimeUpdate.schedule_relationship`: the vehicle will not stop here.
+const STOP_SKIPPED: i32 = 1;
+/// `TripDescriptor.schedule_relationship`: the trip will not run.
+const TRIP_CANCELED: i32 = 3;
+
+#[derive(Clone, PartialEq, Message)]
+pub struct FeedMessage {
+    #[prost(message, repeated, tag = "2")]
+    pub entity: Vec<FeedEntity>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct FeedEntity {
+    #[prost(string, optional, tag = "1")]
+    pub id: Option<String>,
+    #[prost(bool, optional, tag = "2")]
+    pub is_deleted: Option<bool>,
+    #[prost(message, optional, tag = "3")]
+    pub trip_update: Option<TripUpdate>,
+    #[prost(message, optional, tag = "4")]
+    pub vehicle: Option<VehiclePosition>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct TripDescriptor {
+    #[prost(string, optional, tag = "1")]
+    pub trip_id: Option<String>,
+    #[prost(int32, optional, tag = "4")]
+    pub schedule_relationship: Option<i32>,
+    #[prost(string, optional, tag = "5")]
+    pub route_id: Option<String>,
+    #[prost(uint32, optional, tag = "6")]
+    pub direction_id: Option<u32>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct TripUpdate {
+    #[prost(message, optional, tag = "1")]
+    pub trip: Option<TripDescriptor>,
+    #[prost(message, repeated, tag = "2")]
+    pub stop_time_update: Vec<StopTimeUpdate>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct StopTimeUpdate {
+    #[prost(uint32, optional, tag = "1")]
+    pub stop_sequence: Option<u32>,
+    #[prost(message, optional, tag = "2")]
+    pub arrival: Option<StopTimeEvent>,
+    #[prost(message, optional, tag = "3")]
+    pub departure: Option<StopTimeEvent>,
+    #[prost(string, optional, tag = "4")]
+    pub stop_id: Option<String>,
+    #[prost(int32, optional, tag = "5")]
+    pub schedule_relationship: Option<i32>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct StopTimeEvent {
+    #[prost(int32, optional, tag = "1")]
+    pub delay: Option<i32>,
+    #[prost(int64, optional, tag = "2")]
+    pub time: Option<i64>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct VehiclePosition {
+    #[prost(message, optional, tag = "1")]
+    pub trip: Option<TripDescriptor>,
+    #[prost(uint32, optional, tag = "3")]
+    pub current_stop_sequence: Option<u32>,
+}
+
+/// Whether `source` is fetched over HTTP rather than read from a file.
+pub fn is_url(source: &str) -> bool {
+    source.starts_with("http://") || source.starts_with("https://")
+}
+
+/// Reads one feed: fetched when `source` is an http(s) URL, otherwise read from
+/// disk. Fetches are answered from the recording when replaying, and saved when
+/// recording.
+async fn read_feed(client: &Client, source: &str) -> Result<FeedMessage, Box<dyn Error>> {
+    let bytes = if is_url(source) {
+        let (status, body) = match replay::replayed_bytes(source, &[]) {
+            Some(replayed) => replayed?,
+            None => {
+                let endpoint =
+                    reqwest::Url::parse(source).map_or_else(|_| source.to_string(), |u| u.path().to_string());
+                let resp = match client.get(source).send().await {
+                    Ok(resp) => resp,
+                    Err(e) => {
+                        metrics::record_request(&endpoint, None);
+                        return Err(e.into());
+                    }
+                };
+                let status = resp.status().as_u16();
+                metrics::record_request(&endpoint, Some(status));
+                let body = resp.bytes().await?.to_vec();
+                replay::save_bytes(source, &[], status, &body);
+                (status, body)
+            }
+        };
+        if status == 429 {
+            return Err("Rate limited".into());
+        }
+        if !(200..300).contains(&status) {
+            return Err(format!("{}: HTTP status {}", source, status).into());
+        }
+        body
+    } else {
+        std::fs::read(source).map_err(|e| format!("{}: {}", source, e))?
+    };
+    FeedMessage::decode(bytes.as_slice()).map_err(|e| {
+        metrics::record_parse_failure("GTFS-rt");
+        format!("{}: {}", source, e).into()
+    })
+}
+
+/// Reads every feed and concatenates their entities, so trip updates and
+/// vehicle positions may come from separate feeds.
+async fn fetch(client: &Client, sources: &[String]) -> Result<FeedMessage, Box<dyn Error>> {
+    let mut combined = FeedMessage::default();
+    for source in sources {
+        combined.entity.extend(read_feed(client, source).await?.entity);
+    }
+    Ok(combined)
+}
+
+/// Predictions for each route group from a single read of the feeds.
+pub async fn group_predictions(
+    client: &Client,
+    sources: &[String],
+    gtfs: &GtfsStore,
+    groups: &[RouteGroup<'_>],
+) -> Vec<Result<RoutePredictions, Box<dyn Error>>> {
+    match fetch(client, sources).await {
+        Ok(feed) => groups
+            .iter()
+            .map(|g| Ok(route_predictions(&feed, gtfs, g.route_id, g.direction_id, &g.stops)))
+            .collect(),
+        Err(e) => groups.iter().map(|_| Err(e.to_string().into())).collect(),
+    }
+}
+
+fn event_time(event: &Option<StopTimeEvent>) -> Option<DateTime<Local>> {
+    event
+        .as_ref()
+        .and_then(|e| e.time)
+        .and_then(|t| Local.timestamp_opt(t, 0).single())
+}
+
+/// Predictions for `stops` of one route/direction, in the same shape as the
+/// V3 API's. Updates for a platform match its parent station, and stops away
+/// are counted along the trip's stop times in the static feed, falling back to
+/// the update's own stop list for trips the feed lacks. Events that carry only
+/// a delay are skipped.
+fn route_predictions(
+    feed: &FeedMessage,
+    gtfs: &GtfsStore,
+    route_id: &str,
+    direction_id: i32,
+    stops: &[&StopConfig],
+) -> RoutePredictions {
+    let matches_route = |trip: &TripDescriptor| {
+        trip.route_id.as_deref().is_none_or(|r| r == route_id)
+            && trip.direction_id.is_none_or(|d| d as i32 == direction_id)
+    };
+
+    // trip_id -> the stop sequence its vehicle is currently at or approaching
+    let vehicle_sequences: HashMap<&str, u32> = feed
+        .entity
+        .iter()
+        .filter(|e| !e.is_deleted.unwrap_or(false))
+        .filter_map(|e| e.vehicle.as_ref())
+        .filter_map(|v| Some((v.trip.as_ref()?.trip_id.as_deref()?, v.current_stop_sequence?)))
+        .collect();
+
+    let mut stations: HashMap<String, Option<String>> = HashMap::new();
+    let mut route_preds: RoutePredictions = HashMap::new();
+    for update in feed.entity.iter().filter(|e| !e.is_deleted.unwrap_or(false)).filter_map(|e| e.trip_update.as_ref()) {
+        let Some(trip) = update.trip.as_ref().filter(|t| matches_route(t)) else { continue };
+        let Some(trip_id) = trip.trip_id.as_deref() else { continue };
+        if trip.schedule_relationship == Some(TRIP_CANCELED) {
+            continue;
+        }
+
+        for stu in &update.stop_time_update {
+            if stu.schedule_relationship == Some(STOP_SKIPPED) {
+                continue;
+            }
+            let Some(stop_id) = stu.stop_id.as_deref() else { continue };
+            let station = stations
+                .entry(stop_id.to_string())
+                .or_insert_with(|| gtfs.parent_station(stop_id));
+            let Some(target) = stops
+                .iter()
+                .find(|s| s.stop_id == stop_id || station.as_deref() == Some(s.stop_id))
+            else {
+                continue;
+            };
+
+            let (arrival, departure) = (event_time(&stu.arrival), event_time(&stu.departure));
+            if arrival.is_none() && departure.is_none() {
+                continue;
+            }
+            let stops_away = match (vehicle_sequences.get(trip_id), stu.stop_sequence) {
+                (Some(&current), Some(target_seq)) if target_seq > current => {
+                    Some(gtfs.stops_between(trip_id, current, target_seq).unwrap_or_else(|| {
+                        update
+                            .stop_time_update
+                            .iter()
+                            .filter_map(|s| s.stop_sequence)
+                            .filter(|seq| *seq > current && *seq <= target_seq)
+                            .count() as i32
+                    }))
+                }
+                _ => None,
+            };
+
+            route_preds
+                .entry(target.stop_id.to_string())
+                .or_default()
+                .insert(trip_id.to_string(), StopPrediction { arrival, departure, stops_away });
+        }
+    }
+    route_preds
+}
@@ -0,0 +1,150 @@
+//! Bunching and gap detection over a stop's live predictions.
+
+use chrono::{DateTime, Local};
+
+use crate::RowData;
+
+/// A wait this many times the scheduled headway counts as a gap.
+const GAP_FACTOR: f64 = 1.5;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum HeadwayEvent {
+    /// The next bus arrives within the bunching threshold after this one
+    Bunched {
+        trip_id: String,
+        next_trip_id: String,
+        headway_secs: i64,
+    },
+    /// The wait between two buses is much longer than scheduled
+    Gap {
+        previous_trip_id: String,
+        trip_id: String,
+        gap_secs: i64,
+        scheduled_secs: i64,
+    },
+}
+
+impl HeadwayEvent {
+    pub fn to_json(&self) -> serde_json::Value {
+        match self {
+            HeadwayEvent::Bunched { trip_id, next_trip_id, headway_secs } => serde_json::json!({
+                "type": "bunched",
+                "trip_id": trip_id,
+                "next_trip_id": next_trip_id,
+                "headway_seconds": headway_secs,
+            }),
+            HeadwayEvent::Gap { previous_trip_id, trip_id, gap_secs, scheduled_secs } => serde_json::json!({
+                "type": "gap",
+                "previous_trip_id": previous_trip_id,
+                "trip_id": trip_id,
+                "gap_seconds": gap_secs,
+                "scheduled_headway_seconds": scheduled_secs,
+            }),
+        }
+    }
+}
+
+/// Median gap between consecutive scheduled departures in `rows`.
+fn scheduled_headway(rows: &[RowData]) -> Option<i64> {
+    let mut times: Vec<DateTime<Local>> = rows.iter().filter_map(|r| r.sched_dt).collect();
+    times.sort();
+    let mut gaps: Vec<i64> = times
+        .windows(2)
+        .map(|w| w[1].signed_duration_since(w[0]).num_seconds())
+        .filter(|g| *g > 0)
+        .collect();
+    gaps.sort_unstable();
+    gaps.get(gaps.len() / 2).copied()
+}
+
+/// Compares consecutive live predictions: buses closer than `bunch_minutes`
+/// are bunched, and waits of `GAP_FACTOR` times the scheduled headway are gaps.
+/// The last live prediction is also compared with the next scheduled-only trip,
+/// so a long wait behind the last tracked bus is flagged too.
+pub fn detect(rows: &[RowData], bunch_minutes: i64) -> Vec<HeadwayEvent> {
+    let mut live: Vec<(&RowData, DateTime<Local>)> = rows.iter().filter_map(|r| r.pred_dt.map(|p| (r, p))).collect();
+    live.sort_by_key(|(_, p)| *p);
+    let scheduled = scheduled_headway(rows);
+
+    let mut events = Vec::new();
+    for pair in live.windows(2) {
+        let ((first, first_at), (second, second_at)) = (pair[0], pair[1]);
+        let headway = second_at.signed_duration_since(first_at).num_seconds();
+        if headway < bunch_minutes * 60 {
+            events.push(HeadwayEvent::Bunched {
+                trip_id: first.trip_id.clone(),
+                next_trip_id: second.trip_id.clone(),
+                headway_secs: headway,
+            });
+        }
+        if let Some(scheduled) = scheduled.filter(|s| headway as f64 >= *s as f64 * GAP_FACTOR) {
+            events.push(HeadwayEvent::Gap {
+                previous_trip_id: first.trip_id.clone(),
+                trip_id: second.trip_id.clone(),
+                gap_secs: headway,
+                scheduled_secs: scheduled,
+            });
+        }
+    }
+
+    // Nothing is tracked behind the last live bus yet: measure to the timetable
+    let last = live.last();
+    let next_scheduled = last.and_then(|(_, last_at)| {
+        rows.iter()
+            .filter(|r| r.pred_dt.is_none())
+            .filter_map(|r| r.sched_dt.filter(|s| s > last_at).map(|s| (r, s)))
+            .min_by_key(|(_, s)| *s)
+    });
+    if let (Some((last, last_at)), Some((next, next_at)), Some(scheduled)) = (last, next_scheduled, scheduled) {
+        let gap = next_at.signed_duration_since(*last_at).num_seconds();
+        if gap as f64 >= scheduled as f64 * GAP_FACTOR {
+            events.push(HeadwayEvent::Gap {
+                previous_trip_id: last.trip_id.clone(),
+                trip_id: next.trip_id.clone(),
+                gap_secs: gap,
+                scheduled_secs: scheduled,
+            });
+        }
+    }
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration, TimeZone};
+
+    fn row(trip_id: &str, sched_min: i64, pred_min: Option<i64>) -> RowData {
+        let t0 = Local.with_ymd_and_hms(2026, 10, 14, 8, 0, 0).unwrap();
+        RowData {
+            trip_id: trip_id.to_string(),
+            headsign: None,
+            sched_dt: Some(t0 + Duration::minutes(sched_min)),
+            pred_dt: pred_min.map(|m| t0 + Duration::minutes(m)),
+            stops_away: None,
+            wheelchair_accessible: None,
+        }
+    }
+
+    #[test]
+    fn flags_gap_behind_last_live_prediction() {
+        // Every 10 minutes; the 10:10 bus runs 8 minutes early and nothing is tracked behind it
+        let rows = [row("A", 0, Some(1)), row("B", 10, Some(2)), row("C", 20, None), row("D", 30, None)];
+        let events = detect(&rows, 0);
+        assert_eq!(
+            events,
+            vec![HeadwayEvent::Gap {
+                previous_trip_id: "B".to_string(),
+                trip_id: "C".to_string(),
+                gap_secs: 18 * 60,
+                scheduled_secs: 10 * 60,
+            }]
+        );
+    }
+
+    #[test]
+    fn no_trailing_gap_when_next_trip_is_on_schedule() {
+        let rows = [row("A", 0, Some(0)), row("B", 10, Some(10)), row("C", 20, None)];
+        assert!(detect(&rows, 0).is_empty());
+    }
+}
@@ -0,0 +1,85 @@
+//! iCalendar (RFC 5545) export of upcoming departures, one VEVENT per trip.
+
+use chrono::{DateTime, Duration, Local, Utc};
+
+use crate::{format_delay, RowData, StopConfig};
+
+/// Formats a time as an iCalendar UTC date-time, e.g. "20261019T114500Z".
+fn ics_time(t: DateTime<Local>) -> String {
+    t.with_timezone(&Utc).format("%Y%m%dT%H%M%SZ").to_string()
+}
+
+/// Escapes TEXT values (backslash, semicolon, comma, newline).
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace('\n', "\\n")
+}
+
+/// Appends a content line, folding it at 75 octets as the spec requires.
+fn push_line(out: &mut String, line: &str) {
+    let mut width = 0;
+    for c in line.chars() {
+        if width + c.len_utf8() > 75 {
+            out.push_str("\r\n ");
+            width = 1;
+        }
+        out.push(c);
+        width += c.len_utf8();
+    }
+    out.push_str("\r\n");
+}
+
+fn push_event(out: &mut String, stop: &StopConfig, row: &RowData, now: DateTime<Local>) {
+    let Some(start) = row.pred_dt.or(row.sched_dt) else { return };
+
+    let headsign = row.headsign.as_deref().unwrap_or("");
+    let summary = if headsign.is_empty() {
+        format!("{} at {}", stop.route_id, stop.name)
+    } else {
+        format!("{} to {} at {}", stop.route_id, headsign, stop.name)
+    };
+
+    let mut description = vec![
+        format!("Route: {}", stop.route_id),
+        format!("Stop: {} ({})", stop.name, stop.stop_id),
+        format!("Headsign: {}", headsign),
+        format!("Trip: {}", row.trip_id),
+    ];
+    if let Some(sched) = row.sched_dt {
+        description.push(format!("Scheduled: {}", sched.format("%H:%M")));
+    }
+    if let Some(pred) = row.pred_dt {
+        let delay = row.delay_seconds().map(|d| format!(" ({})", format_delay(d))).unwrap_or_default();
+        description.push(format!("Predicted: {}{}", pred.format("%H:%M:%S"), delay));
+    }
+
+    push_line(out, "BEGIN:VEVENT");
+    push_line(out, &format!("UID:{}-{}@b60", row.trip_id, stop.stop_id));
+    push_line(out, &format!("DTSTAMP:{}", ics_time(now)));
+    push_line(out, &format!("DTSTART:{}", ics_time(start)));
+    push_line(out, &format!("DTEND:{}", ics_time(start + Duration::minutes(1))));
+    push_line(out, &format!("SUMMARY:{}", escape(&summary)));
+    push_line(out, &format!("LOCATION:{}", escape(stop.name)));
+    push_line(out, &format!("DESCRIPTION:{}", escape(&description.join("\n"))));
+    push_line(out, &format!("CATEGORIES:{}", escape(stop.route_id)));
+    push_line(out, "END:VEVENT");
+}
+
+/// Builds a VCALENDAR with every fetched trip of every stop.
+pub fn calendar(stops: &[&StopConfig], rows: &[Vec<RowData>], now: DateTime<Local>) -> String {
+    let mut out = String::new();
+    push_line(&mut out, "BEGIN:VCALENDAR");
+    push_line(&mut out, "VERSION:2.0");
+    push_line(&mut out, "PRODID:-//b60//MBTA departures//EN");
+    push_line(&mut out, "CALSCALE:GREGORIAN");
+    push_line(&mut out, "X-WR-CALNAME:MBTA departures");
+    for (stop, rows) in stops.iter().zip(rows) {
+        for row in rows {
+            push_event(&mut out, stop, row, now);
+        }
+    }
+    push_line(&mut out, "END:VCALENDAR");
+    out
+}
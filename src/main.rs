@@ -1,489 +1,766 @@
+mod accuracy;
+mod cli;
+mod gtfs;
+mod gtfs_rt;
+mod headway;
+mod ics;
+mod mbta;
+mod metrics;
+mod notify;
+mod plan;
+mod provider;
+mod publish;
+mod record;
+mod replay;
+mod report;
+mod server;
+mod statusbar;
+
 use chrono::{DateTime, Duration, Local};
-use reqwest::Client;
-use serde::Deserialize;
+use futures::future::join_all;
+use provider::{Providers, RouteGroup};
 use std::collections::HashMap;
 use std::error::Error;
+use std::io::IsTerminal;
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
 
-const BASE_URL: &str = "https://api-v3.mbta.com";
+/// How far ahead schedules are fetched when no horizon is configured, so dense
+/// headways are matched completely.
+const SCHEDULE_WINDOW_MINUTES: i64 = 120;
 
-#[derive(Debug, Deserialize)]
-struct ApiResponse<T> {
-    data: Vec<T>,
+#[derive(Clone)]
+struct StopConfig {
+    name: &'static str,
+    route_id: &'static str,
+    stop_id: &'static str,
+    direction_id: i32,
+    is_origin: bool,
+    window: WindowOverrides,
+    /// Minutes it takes to walk to the stop
+    walk_minutes: Option<i64>,
+    /// Name of the provider the stop's departures come from (see `--provider`)
+    provider: &'static str,
 }
 
-#[derive(Debug, Deserialize)]
-struct Resource<A, R> {
-    attributes: A,
-    relationships: R,
+/// Per-stop overrides for the global `WindowSettings`.
+#[derive(Clone, Copy, Default)]
+struct WindowOverrides {
+    trips: Option<usize>,
+    horizon_minutes: Option<i64>,
+    past_cutoff_minutes: Option<i64>,
+    lookback_minutes: Option<i64>,
 }
 
-#[derive(Debug, Deserialize)]
-struct ScheduleAttributes {
-    arrival_time: Option<String>,
-    departure_time: Option<String>,
+/// Which trips are fetched and shown for a stop.
+#[derive(Clone, Copy)]
+struct WindowSettings {
+    /// Number of trips shown
+    trips: usize,
+    /// Only show trips departing within this many minutes (unlimited if `None`)
+    horizon_minutes: Option<i64>,
+    /// Keep trips that departed up to this many minutes ago
+    past_cutoff_minutes: i64,
+    /// How far back schedules are fetched to catch delayed trips
+    lookback_minutes: i64,
+    /// Hide trips known not to be wheelchair accessible
+    accessible_only: bool,
 }
 
-#[derive(Debug, Deserialize)]
-struct ScheduleRelationships {
-    trip: DataWrapper,
+impl Default for WindowSettings {
+    fn default() -> Self {
+        WindowSettings {
+            trips: 3,
+            horizon_minutes: None,
+            past_cutoff_minutes: 5,
+            lookback_minutes: 30,
+            accessible_only: false,
+        }
+    }
 }
 
-#[derive(Debug, Deserialize)]
-struct PredictionAttributes {
-    arrival_time: Option<String>,
-    departure_time: Option<String>,
+impl WindowSettings {
+    /// Applies a stop's overrides on top of these settings.
+    fn for_stop(&self, stop: &StopConfig) -> WindowSettings {
+        WindowSettings {
+            trips: stop.window.trips.unwrap_or(self.trips),
+            horizon_minutes: stop.window.horizon_minutes.or(self.horizon_minutes),
+            past_cutoff_minutes: stop.window.past_cutoff_minutes.unwrap_or(self.past_cutoff_minutes),
+            lookback_minutes: stop.window.lookback_minutes.unwrap_or(self.lookback_minutes),
+            accessible_only: self.accessible_only,
+        }
+    }
 }
 
-#[derive(Debug, Deserialize)]
-struct PredictionRelationships {
-    trip: DataWrapper,
-    vehicle: Option<OptionalDataWrapper>,
-    stop: Option<DataWrapper>,
+#[derive(Debug, Clone)]
+struct RowData {
+    trip_id: String,
+    headsign: Option<String>,
+    sched_dt: Option<DateTime<Local>>,
+    pred_dt: Option<DateTime<Local>>,
+    stops_away: Option<i32>,
+    /// Whether the trip is wheelchair accessible, when the schedule says
+    wheelchair_accessible: Option<bool>,
 }
 
-#[derive(Debug, Deserialize)]
-struct OptionalDataWrapper {
-    data: Option<IdWrapper>,
+impl RowData {
+    /// Deviation of the prediction from the schedule in seconds (positive = late).
+    fn delay_seconds(&self) -> Option<i64> {
+        match (self.sched_dt, self.pred_dt) {
+            (Some(sched), Some(pred)) => Some(pred.signed_duration_since(sched).num_seconds()),
+            _ => None,
+        }
+    }
 }
 
-#[derive(Debug, Deserialize)]
-struct IncludedResource {
-    #[serde(rename = "type")]
-    resource_type: String,
-    id: String,
-    #[serde(default)]
-    relationships: serde_json::Value,
+/// Schedule adherence of a live trip, bucketed by delay thresholds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DelayStatus {
+    Early,
+    OnTime,
+    Late,
+    VeryLate,
 }
 
-#[derive(Debug, Deserialize)]
-struct RouteStopsResponse {
-    data: Vec<RouteStop>,
-}
+/// Trips leaving at least this many seconds before schedule are early.
+const EARLY_THRESHOLD_SECS: i64 = 60;
+/// Trips at least this many seconds behind schedule are late.
+const LATE_THRESHOLD_SECS: i64 = 120;
+/// Trips at least this many seconds behind schedule are very late.
+const VERY_LATE_THRESHOLD_SECS: i64 = 300;
+
+impl DelayStatus {
+    fn from_delay(delay_secs: i64) -> DelayStatus {
+        if delay_secs <= -EARLY_THRESHOLD_SECS {
+            DelayStatus::Early
+        } else if delay_secs >= VERY_LATE_THRESHOLD_SECS {
+            DelayStatus::VeryLate
+        } else if delay_secs >= LATE_THRESHOLD_SECS {
+            DelayStatus::Late
+        } else {
+            DelayStatus::OnTime
+        }
+    }
 
-#[derive(Debug, Deserialize)]
-struct RouteStop {
-    id: String,
-}
+    fn as_str(&self) -> &'static str {
+        match self {
+            DelayStatus::Early => "early",
+            DelayStatus::OnTime => "on_time",
+            DelayStatus::Late => "late",
+            DelayStatus::VeryLate => "very_late",
+        }
+    }
 
-#[derive(Debug, Deserialize)]
-struct PredictionApiResponse {
-    data: Vec<Resource<PredictionAttributes, PredictionRelationships>>,
-    #[serde(default)]
-    included: Vec<IncludedResource>,
+    /// ANSI SGR color code for this status.
+    fn color(&self) -> &'static str {
+        match self {
+            DelayStatus::Early => "36",
+            DelayStatus::OnTime => "32",
+            DelayStatus::Late => "33",
+            DelayStatus::VeryLate => "31",
+        }
+    }
 }
 
-#[derive(Debug, Deserialize)]
-struct DataWrapper {
-    data: IdWrapper,
+/// Rendering options shared by all stops.
+struct DisplayOptions {
+    /// Emit ANSI colors
+    color: bool,
+    layout: cli::Layout,
+    /// Terminal width in columns, if known
+    width: Option<usize>,
+    /// Flag bunching and gaps, with this bunching threshold in minutes
+    bunch_minutes: Option<i64>,
 }
 
-#[derive(Debug, Deserialize)]
-struct IdWrapper {
-    id: String,
+/// Grid column width bounds; cells wider than the maximum are truncated.
+const MIN_COL_WIDTH: usize = 32;
+const MAX_COL_WIDTH: usize = 44;
+
+/// Below this terminal width the automatic layout switches from grid to list.
+const NARROW_WIDTH: usize = 60;
+
+/// Live trips departing within this many minutes are highlighted as imminent.
+const IMMINENT_MINUTES: i64 = 2;
+
+/// ANSI SGR codes for the grid's styling.
+const STYLE_BOLD: &str = "1";
+const STYLE_DIM: &str = "2";
+const STYLE_IMMINENT: &str = "1;31";
+const STYLE_ERROR: &str = "31";
+const STYLE_HEADWAY: &str = "33";
+const STYLE_OUTAGE: &str = "1;33";
+
+/// ANSI SGR code for a route's MBTA brand color.
+fn route_color(route_id: &str) -> &'static str {
+    if route_id.starts_with("Green") {
+        "1;38;2;0;132;61"
+    } else if route_id == "Red" || route_id == "Mattapan" {
+        "1;38;2;218;41;28"
+    } else if route_id == "Orange" {
+        "1;38;2;237;139;0"
+    } else if route_id == "Blue" {
+        "1;38;2;0;61;165"
+    } else if route_id.starts_with("CR-") {
+        "1;38;2;128;39;108"
+    } else {
+        // Buses
+        "1;38;2;255;199;44"
+    }
 }
 
-#[derive(Clone)]
-struct StopConfig {
-    route_id: &'static str,
-    stop_id: &'static str,
-    direction_id: i32,
-    is_origin: bool,
+/// A live prediction for one trip at one monitored stop.
+struct StopPrediction {
+    arrival: Option<DateTime<Local>>,
+    departure: Option<DateTime<Local>>,
+    stops_away: Option<i32>,
 }
 
-#[derive(Debug, Clone)]
-struct RowData {
-    sched_dt: Option<DateTime<Local>>,
-    pred_dt: Option<DateTime<Local>>,
-    stops_away: Option<i32>,
+/// One scheduled stop time, from `/schedules` or a GTFS feed.
+struct ScheduledTrip {
+    trip_id: String,
+    headsign: Option<String>,
+    arrival: Option<DateTime<Local>>,
+    departure: Option<DateTime<Local>>,
+    /// Whether the trip is wheelchair accessible, when the schedule says
+    wheelchair_accessible: Option<bool>,
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    let client = Client::new();
-    let now = Local::now();
+/// Predictions for every monitored stop of a route/direction: stop_id -> trip_id -> prediction.
+type RoutePredictions = HashMap<String, HashMap<String, StopPrediction>>;
 
-    // Define Stops - Route 60
-    let stop_kenmore = StopConfig {
-        route_id: "60",
-        stop_id: "place-kencl",
-        direction_id: 0,
-        is_origin: true,
-    };
+/// A closed elevator or escalator at a monitored stop's station.
+struct Outage {
+    /// "Elevator" or "Escalator"
+    kind: &'static str,
+    /// Which one, e.g. "Busway to lobby"
+    facility: String,
+}
 
-    let stop_brookline_ave = StopConfig {
-        route_id: "60",
-        stop_id: "1519",
-        direction_id: 0,
-        is_origin: false,
-    };
+/// Outages at each monitored stop's station: stop_id -> outages.
+type StopOutages = HashMap<String, Vec<Outage>>;
 
-    let stop_pearl = StopConfig {
-        route_id: "60",
-        stop_id: "11366",
-        direction_id: 0,
-        is_origin: false,
-    };
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let options = cli::parse_args(std::env::args().skip(1)).unwrap_or_else(|e| {
+        eprintln!("error: {}\n\n{}", e, cli::USAGE);
+        std::process::exit(2);
+    });
+    let err_color = options.color.enabled(std::io::stderr().is_terminal());
 
-    let stop_high = StopConfig {
-        route_id: "60",
-        stop_id: "1553",
-        direction_id: 1,
-        is_origin: false,
-    };
+    let groups = select_stops(monitored_stops(), &options.stops).unwrap_or_else(|e| {
+        eprintln!("error: {}", e);
+        std::process::exit(2);
+    });
+    let all_stops: Vec<StopConfig> = groups.iter().flat_map(|(_, stops)| stops.clone()).collect();
 
-    // Define Stops - Green Line D
-    let stop_copley = StopConfig {
-        route_id: "Green-D",
-        stop_id: "place-coecl",
-        direction_id: 0,
-        is_origin: true,
-    };
+    if options.command == cli::Command::Report {
+        // Works from the recorded history alone
+        return report::run(&all_stops, &options);
+    }
+    replay::init(&options)?;
+    let providers = Providers::open(&options)?;
+
+    match options.command {
+        cli::Command::Serve => return server::serve(&providers, &all_stops, &options).await,
+        cli::Command::Notify => return notify::run(&providers, &all_stops, &options).await,
+        cli::Command::Plan => return plan::run(&providers, &configured_journeys(), &options).await,
+        cli::Command::Transfers => return plan::run_transfers(&providers, &configured_journeys(), &options).await,
+        cli::Command::Publish => return publish::run(&providers, &all_stops, &options).await,
+        cli::Command::Record => return record::run(&providers, &all_stops, &options).await,
+        cli::Command::Report | cli::Command::Show => {}
+    }
 
-    let stop_brookline = StopConfig {
-        route_id: "Green-D",
-        stop_id: "place-bvmnl",
-        direction_id: 1,
-        is_origin: true,
+    // Prediction error ranges from the recorded history, one per stop
+    let accuracy = if options.confidence {
+        accuracy::load_for_stops(&all_stops, &options)?
+    } else {
+        Vec::new()
     };
 
-    // 1. Fetch Data Concurrently
-    let (res_kenmore, res_brookline_ave, res_pearl, res_high, res_copley, res_brookline) =
-        tokio::join!(
-            get_schedule_and_predictions(&client, &stop_kenmore, now),
-            get_schedule_and_predictions(&client, &stop_brookline_ave, now),
-            get_schedule_and_predictions(&client, &stop_pearl, now),
-            get_schedule_and_predictions(&client, &stop_high, now),
-            get_schedule_and_predictions(&client, &stop_copley, now),
-            get_schedule_and_predictions(&client, &stop_brookline, now)
-        );
-
-    // Check for rate limiting first
-    if res_kenmore.is_err() && res_kenmore.as_ref().unwrap_err().to_string() == "Rate limited"
-        || res_brookline_ave.is_err()
-            && res_brookline_ave.as_ref().unwrap_err().to_string() == "Rate limited"
-        || res_pearl.is_err() && res_pearl.as_ref().unwrap_err().to_string() == "Rate limited"
-        || res_high.is_err() && res_high.as_ref().unwrap_err().to_string() == "Rate limited"
-        || res_copley.is_err() && res_copley.as_ref().unwrap_err().to_string() == "Rate limited"
-        || res_brookline.is_err()
-            && res_brookline.as_ref().unwrap_err().to_string() == "Rate limited"
-    {
-        eprintln!("⚠️  MBTA API rate limit exceeded. Please wait a moment and try again.");
-        std::process::exit(1);
+    if options.format == cli::OutputFormat::I3bar {
+        println!("{}", statusbar::I3BAR_HEADER);
     }
 
-    let rows_kenmore = res_kenmore.unwrap_or_else(|e| {
-        eprintln!("⚠️  Error fetching Kenmore data: {}", e);
-        vec![]
-    });
-    let rows_brookline_ave = res_brookline_ave.unwrap_or_else(|e| {
-        eprintln!("⚠️  Error fetching Brookline Ave data: {}", e);
-        vec![]
-    });
-    let rows_pearl = res_pearl.unwrap_or_else(|e| {
-        eprintln!("⚠️  Error fetching Pearl St data: {}", e);
-        vec![]
-    });
-    let rows_high = res_high.unwrap_or_else(|e| {
-        eprintln!("⚠️  Error fetching High St data: {}", e);
-        vec![]
-    });
-    let rows_copley = res_copley.unwrap_or_else(|e| {
-        eprintln!("⚠️  Error fetching Copley data: {}", e);
-        vec![]
-    });
-    let rows_brookline = res_brookline.unwrap_or_else(|e| {
-        eprintln!("⚠️  Error fetching Brookline Village data: {}", e);
-        vec![]
-    });
+    loop {
+        let now = replay::now();
+
+        // 1. Fetch Data Concurrently (one predictions request per route/direction)
+        let (rows, outages) = tokio::join!(
+            fetch_filtered_rows(&providers, &all_stops, &options.window, err_color, now),
+            async {
+                // Only the grid shows outages
+                if options.format == cli::OutputFormat::Grid {
+                    fetch_outages(&providers, &all_stops, err_color, now).await
+                } else {
+                    HashMap::new()
+                }
+            }
+        );
+        match rows {
+            Some(rows) => render(&groups, &rows, &outages, &accuracy, &options, now)?,
+            None => {
+                eprintln!(
+                    "{}",
+                    paint("⚠️  MBTA API rate limit exceeded. Please wait a moment and try again.", STYLE_ERROR, err_color)
+                );
+                if options.watch.is_none() {
+                    std::process::exit(1);
+                }
+            }
+        }
 
-    // Filter rows > 5 mins ago, drop past schedule-only when live data exists
-    let filter_rows = |rows: Vec<RowData>| -> Vec<RowData> {
-        let filtered: Vec<RowData> = rows.into_iter()
-            .filter(|r| {
-                let s_diff = r
-                    .sched_dt
-                    .map(|t| t.signed_duration_since(now).num_minutes())
-                    .unwrap_or(0);
-                let p_diff = r
-                    .pred_dt
-                    .map(|t| t.signed_duration_since(now).num_minutes())
-                    .unwrap_or(s_diff);
-                s_diff > -5 || p_diff > -5
-            })
-            .collect();
-        let has_live = filtered.iter().any(|r| r.pred_dt.is_some());
-        if has_live {
-            filtered.into_iter()
-                .filter(|r| r.pred_dt.is_some() || r.sched_dt.map(|t| t > now).unwrap_or(false))
-                .collect()
-        } else {
-            filtered
+        match options.watch {
+            Some(interval) => tokio::time::sleep(interval).await,
+            None => break,
         }
-    };
+    }
 
-    let rows_kenmore = filter_rows(rows_kenmore);
-    let rows_brookline_ave = filter_rows(rows_brookline_ave);
-    let rows_pearl = filter_rows(rows_pearl);
-    let rows_high = filter_rows(rows_high);
-    let rows_copley = filter_rows(rows_copley);
-    let rows_brookline = filter_rows(rows_brookline);
+    Ok(())
+}
 
-    // 2. Show Schedule
+/// The stops shown by `b60`, grouped under a title per route.
+fn monitored_stops() -> Vec<(&'static str, Vec<StopConfig>)> {
+    // Define Stops - Route 60
     let route60_stops = vec![
-        format_stop_data("Kenmore (outbound)", &rows_kenmore, now),
-        format_stop_data("Brookline Ave @ Fullerton (outbound)", &rows_brookline_ave, now),
-        format_stop_data("Pearl St @ Brookline Village (outbound)", &rows_pearl, now),
-        format_stop_data("High St @ Highland Rd (inbound)", &rows_high, now),
+        StopConfig {
+            name: "Kenmore (outbound)",
+            route_id: "60",
+            stop_id: "place-kencl",
+            direction_id: 0,
+            is_origin: true,
+            window: WindowOverrides::default(),
+            walk_minutes: None,
+            provider: "mbta",
+        },
+        StopConfig {
+            name: "Brookline Ave @ Fullerton (outbound)",
+            route_id: "60",
+            stop_id: "1519",
+            direction_id: 0,
+            is_origin: false,
+            window: WindowOverrides::default(),
+            walk_minutes: None,
+            provider: "mbta",
+        },
+        StopConfig {
+            name: "Pearl St @ Brookline Village (outbound)",
+            route_id: "60",
+            stop_id: "11366",
+            direction_id: 0,
+            is_origin: false,
+            window: WindowOverrides::default(),
+            walk_minutes: Some(9),
+            provider: "mbta",
+        },
+        StopConfig {
+            name: "High St @ Highland Rd (inbound)",
+            route_id: "60",
+            stop_id: "1553",
+            direction_id: 1,
+            is_origin: false,
+            window: WindowOverrides::default(),
+            walk_minutes: None,
+            provider: "mbta",
+        },
     ];
-    print_stops_grid("Route 60:", route60_stops);
 
+    // Define Stops - Green Line D
     let green_line_stops = vec![
-        format_stop_data("Copley (to Riverside)", &rows_copley, now),
-        format_stop_data("Brookline Village (to Kenmore)", &rows_brookline, now),
+        StopConfig {
+            name: "Copley (to Riverside)",
+            route_id: "Green-D",
+            stop_id: "place-coecl",
+            direction_id: 0,
+            is_origin: true,
+            window: WindowOverrides::default(),
+            walk_minutes: None,
+            provider: "mbta",
+        },
+        StopConfig {
+            name: "Brookline Village (to Kenmore)",
+            route_id: "Green-D",
+            stop_id: "place-bvmnl",
+            direction_id: 1,
+            is_origin: true,
+            window: WindowOverrides::default(),
+            walk_minutes: None,
+            provider: "mbta",
+        },
     ];
-    print_stops_grid("Green Line D:", green_line_stops);
 
-    Ok(())
+    vec![("Route 60:", route60_stops), ("Green Line D:", green_line_stops)]
 }
 
-async fn get_schedule_and_predictions(
-    client: &Client,
-    stop: &StopConfig,
-    now: DateTime<Local>,
-) -> Result<Vec<RowData>, Box<dyn Error>> {
-    // Look back 30 mins to catch delayed trips
-    let lookback_time = now - Duration::minutes(30);
-    let sched_url = format!("{}/schedules", BASE_URL);
-    let sched_params = [
-        ("filter[stop]", stop.stop_id.to_string()),
-        ("filter[route]", stop.route_id.to_string()),
-        ("filter[direction_id]", stop.direction_id.to_string()),
-        ("sort", "arrival_time".to_string()),
-        (
-            "filter[min_time]",
-            lookback_time.format("%H:%M").to_string(),
-        ),
-        ("page[limit]", "20".to_string()), // Request more to ensure we have enough after filtering
-    ];
+/// One ride of a journey: board `board_stop_id` and ride to `alight_stop_id`.
+#[derive(Clone)]
+struct LegConfig {
+    route_id: &'static str,
+    direction_id: i32,
+    board_name: &'static str,
+    board_stop_id: &'static str,
+    /// Whether the boarding stop is the route's origin (use departure times)
+    board_is_origin: bool,
+    alight_name: &'static str,
+    alight_stop_id: &'static str,
+    /// Minutes walking to the boarding stop: from home for the first leg,
+    /// from the previous leg's alighting stop otherwise
+    walk_minutes: i64,
+    /// Name of the provider the leg's departures come from (see `--provider`)
+    provider: &'static str,
+}
+
+struct JourneyConfig {
+    name: &'static str,
+    legs: Vec<LegConfig>,
+}
 
-    let sched_resp = client
-        .get(&sched_url)
-        .header("accept", "application/vnd.api+json")
-        .query(&sched_params)
-        .send()
-        .await?;
+/// The journeys `b60 plan` chains legs for.
+fn configured_journeys() -> Vec<JourneyConfig> {
+    vec![
+        // Route 60 to Brookline Village, then the D inbound
+        JourneyConfig {
+            name: "fullerton-to-copley",
+            legs: vec![
+                LegConfig {
+                    route_id: "60",
+                    direction_id: 0,
+                    board_name: "Brookline Ave @ Fullerton",
+                    board_stop_id: "1519",
+                    board_is_origin: false,
+                    alight_name: "Pearl St @ Brookline Village",
+                    alight_stop_id: "11366",
+                    walk_minutes: 5,
+                    provider: "mbta",
+                },
+                LegConfig {
+                    route_id: "Green-D",
+                    direction_id: 1,
+                    board_name: "Brookline Village",
+                    board_stop_id: "place-bvmnl",
+                    board_is_origin: true,
+                    alight_name: "Copley",
+                    alight_stop_id: "place-coecl",
+                    walk_minutes: 3,
+                    provider: "mbta",
+                },
+            ],
+        },
+        // The D outbound to Kenmore, then Route 60 to Brookline Village
+        JourneyConfig {
+            name: "copley-to-brookline-village",
+            legs: vec![
+                LegConfig {
+                    route_id: "Green-D",
+                    direction_id: 0,
+                    board_name: "Copley",
+                    board_stop_id: "place-coecl",
+                    board_is_origin: true,
+                    alight_name: "Kenmore",
+                    alight_stop_id: "place-kencl",
+                    walk_minutes: 5,
+                    provider: "mbta",
+                },
+                LegConfig {
+                    route_id: "60",
+                    direction_id: 0,
+                    board_name: "Kenmore",
+                    board_stop_id: "place-kencl",
+                    board_is_origin: true,
+                    alight_name: "Pearl St @ Brookline Village",
+                    alight_stop_id: "11366",
+                    walk_minutes: 4,
+                    provider: "mbta",
+                },
+            ],
+        },
+    ]
+}
 
-    // Check for rate limiting
-    if sched_resp.status().as_u16() == 429 {
-        return Err("Rate limited".into());
+/// Narrows the groups to the given stop IDs (all stops when empty), dropping
+/// groups left without stops.
+fn select_stops(
+    groups: Vec<(&'static str, Vec<StopConfig>)>,
+    stop_ids: &[String],
+) -> Result<Vec<(&'static str, Vec<StopConfig>)>, String> {
+    if stop_ids.is_empty() {
+        return Ok(groups);
+    }
+    if let Some(unknown) = stop_ids
+        .iter()
+        .find(|id| !groups.iter().any(|(_, stops)| stops.iter().any(|s| s.stop_id == id.as_str())))
+    {
+        return Err(format!("no monitored stop with ID {}", unknown));
     }
 
-    let sched_text = sched_resp.text().await?;
+    Ok(groups
+        .into_iter()
+        .map(|(title, stops)| {
+            let stops: Vec<StopConfig> = stops
+                .into_iter()
+                .filter(|s| stop_ids.iter().any(|id| id == s.stop_id))
+                .collect();
+            (title, stops)
+        })
+        .filter(|(_, stops)| !stops.is_empty())
+        .collect())
+}
 
-    // Check for errors in the text response manually or just try to parse
-    let sched_resp: ApiResponse<Resource<ScheduleAttributes, ScheduleRelationships>> =
-        match serde_json::from_str(&sched_text) {
-            Ok(v) => v,
-            Err(e) => {
-                eprintln!("Failed to parse Sched JSON: {}", e);
-                eprintln!("Raw Body: {}", sched_text);
-                return Err(Box::new(e));
-            }
-        };
+/// Fetches and filters rows for every stop, in the same order as `stops`.
+/// Returns `None` when the MBTA API rate limit was hit.
+async fn fetch_filtered_rows(
+    providers: &Providers,
+    stops: &[StopConfig],
+    window: &WindowSettings,
+    err_color: bool,
+    now: DateTime<Local>,
+) -> Option<Vec<Vec<RowData>>> {
+    let results = fetch_stops(providers, stops, window, now).await;
 
-    // 2. Fetch Predictions (with vehicle data)
-    let pred_url = format!("{}/predictions", BASE_URL);
-    let pred_params = [
-        ("filter[stop]", stop.stop_id.to_string()),
-        ("filter[route]", stop.route_id.to_string()),
-        ("filter[direction_id]", stop.direction_id.to_string()),
-        ("sort", "arrival_time".to_string()),
-        ("page[limit]", "3".to_string()),
-        ("include", "vehicle,stop".to_string()),
-    ];
+    // Check for rate limiting first
+    if results
+        .iter()
+        .any(|r| r.as_ref().is_err_and(|e| e.to_string() == "Rate limited"))
+    {
+        return None;
+    }
 
-    let pred_resp = client
-        .get(&pred_url)
-        .header("accept", "application/vnd.api+json")
-        .query(&pred_params)
-        .send()
-        .await?;
+    let rows = stops
+        .iter()
+        .zip(results)
+        .map(|(stop, res)| {
+            let rows = res.unwrap_or_else(|e| {
+                let msg = format!("⚠️  Error fetching {} data: {}", stop.name, e);
+                eprintln!("{}", paint(&msg, STYLE_ERROR, err_color));
+                vec![]
+            });
+            filter_rows(rows, &window.for_stop(stop), now)
+        })
+        .collect();
 
-    // Check for rate limiting
-    if pred_resp.status().as_u16() == 429 {
-        return Err("Rate limited".into());
+    Some(rows)
+}
+
+/// Writes one refresh in the selected output format.
+fn render(
+    groups: &[(&'static str, Vec<StopConfig>)],
+    rows: &[Vec<RowData>],
+    outages: &StopOutages,
+    accuracy: &[accuracy::Accuracy],
+    options: &cli::Options,
+    now: DateTime<Local>,
+) -> Result<(), Box<dyn Error>> {
+    let all_stops: Vec<&StopConfig> = groups.iter().flat_map(|(_, stops)| stops).collect();
+
+    match options.format {
+        cli::OutputFormat::Json => {
+            let stops_json: Vec<serde_json::Value> = all_stops
+                .iter()
+                .zip(rows)
+                .map(|(stop, rows)| stop_json(stop, rows, &options.window.for_stop(stop)))
+                .collect();
+            let doc = serde_json::json!({
+                "generated_at": now.to_rfc3339(),
+                "stops": stops_json,
+            });
+            println!("{}", serde_json::to_string_pretty(&doc)?);
+        }
+        cli::OutputFormat::Status => {
+            println!("{}", statusbar::status_line(&all_stops, rows, &options.window, now));
+        }
+        cli::OutputFormat::Waybar => {
+            println!("{}", statusbar::waybar(&all_stops, rows, &options.window, now));
+        }
+        cli::OutputFormat::I3bar => {
+            println!("{},", statusbar::i3bar(&all_stops, rows, &options.window, now));
+        }
+        cli::OutputFormat::Ics => {
+            print!("{}", ics::calendar(&all_stops, rows, now));
+        }
+        cli::OutputFormat::Csv => unreachable!("--format csv is rejected outside report"),
+        cli::OutputFormat::Grid => {
+            // 2. Show Schedule
+            let display = DisplayOptions {
+                color: options.color.enabled(std::io::stdout().is_terminal()),
+                layout: options.layout,
+                width: terminal_width(),
+                bunch_minutes: Some(options.bunch_minutes),
+            };
+            if options.watch.is_some() && std::io::stdout().is_terminal() {
+                // Redraw in place
+                print!("\x1b[2J\x1b[H");
+            }
+            let mut rows_iter = rows.iter();
+            let mut accuracy_iter = accuracy.iter();
+            for (title, stops) in groups {
+                let displays = stops
+                    .iter()
+                    .map(|stop| {
+                        let window = options.window.for_stop(stop);
+                        let rows = rows_iter.next().map(|r| r.as_slice()).unwrap_or_default();
+                        let walk = options.leave.then(|| walk_minutes(options, stop));
+                        let confidence = accuracy_iter.next();
+                        let mut stop_display = format_stop_data(stop.name, rows, &window, walk, confidence, &display, now);
+                        // Outages go above the trips, where they can't be missed
+                        let warnings = outages.get(stop.stop_id).into_iter().flatten().map(|o| {
+                            paint(&format!("♿ {} out: {}", o.kind, o.facility), STYLE_OUTAGE, display.color)
+                        });
+                        stop_display.times.splice(0..0, warnings);
+                        stop_display
+                    })
+                    .collect();
+                let title_style = stops.first().map(|s| route_color(s.route_id)).unwrap_or(STYLE_BOLD);
+                print_stops(title, title_style, displays, &display);
+            }
+        }
     }
 
-    let pred_text = pred_resp.text().await?;
+    Ok(())
+}
 
-    let pred_resp: PredictionApiResponse =
-        match serde_json::from_str(&pred_text) {
-            Ok(v) => v,
-            Err(e) => {
-                eprintln!("Failed to parse Pred JSON: {}", e);
-                eprintln!("Raw Body: {}", pred_text);
-                return Err(Box::new(e));
-            }
-        };
+/// Walking time to a stop: `--walk` when given, else the stop's configured time.
+fn walk_minutes(options: &cli::Options, stop: &StopConfig) -> i64 {
+    options.walk_minutes.or(stop.walk_minutes).unwrap_or(0)
+}
 
-    // Extract vehicle current stop IDs and build child->parent stop map
-    let mut vehicle_stop_ids: HashMap<String, String> = HashMap::new(); // vehicle_id -> child stop ID
-    let mut stop_parent_map: HashMap<String, String> = HashMap::new(); // child stop ID -> parent station ID
-    for inc in &pred_resp.included {
-        if inc.resource_type == "vehicle" {
-            if let Some(stop_data) = inc.relationships.get("stop")
-                .and_then(|s| s.get("data"))
-                .and_then(|d| d.get("id"))
-                .and_then(|id| id.as_str()) {
-                vehicle_stop_ids.insert(inc.id.clone(), stop_data.to_string());
+/// Fetches rows for every stop, returned in the same order as `stops`.
+///
+/// Stops are grouped by provider, route and direction so that each provider
+/// can fetch a route's predictions at once; schedules stay per stop.
+async fn fetch_stops(
+    providers: &Providers,
+    stops: &[StopConfig],
+    window: &WindowSettings,
+    now: DateTime<Local>,
+) -> Vec<Result<Vec<RowData>, Box<dyn Error>>> {
+    let mut provider_groups: Vec<(&str, Vec<RouteGroup>)> = Vec::new();
+    for stop in stops {
+        let i = match provider_groups.iter().position(|(name, _)| *name == stop.provider) {
+            Some(i) => i,
+            None => {
+                provider_groups.push((stop.provider, Vec::new()));
+                provider_groups.len() - 1
             }
-        } else if inc.resource_type == "stop" {
-            let parent_id = inc.relationships.get("parent_station")
-                .and_then(|ps| ps.get("data"))
-                .and_then(|d| d.get("id"))
-                .and_then(|id| id.as_str())
-                .map(|s| s.to_string())
-                .unwrap_or_else(|| inc.id.clone());
-            stop_parent_map.insert(inc.id.clone(), parent_id);
-        }
-    }
-    // Collect all stop IDs we need to resolve (vehicle stops + prediction stops)
-    let pred_stop_ids: Vec<String> = pred_resp.data.iter()
-        .filter_map(|p| p.relationships.stop.as_ref().map(|s| s.data.id.clone()))
-        .collect();
-    let all_stop_ids: Vec<String> = vehicle_stop_ids.values().cloned()
-        .chain(pred_stop_ids.into_iter())
-        .collect();
-    // Batch-resolve unknown child stop IDs to their parent stations
-    let unknown_ids: Vec<String> = all_stop_ids.iter()
-        .filter(|id| !stop_parent_map.contains_key(*id))
-        .cloned()
-        .collect::<std::collections::HashSet<_>>()
-        .into_iter()
-        .collect();
-    if !unknown_ids.is_empty() {
-        let ids_param = unknown_ids.join(",");
-        if let Ok(resp) = client
-            .get(&format!("{}/stops", BASE_URL))
-            .header("accept", "application/vnd.api+json")
-            .query(&[("filter[id]", &ids_param)])
-            .send()
-            .await
+        };
+        let groups = &mut provider_groups[i].1;
+        match groups
+            .iter_mut()
+            .find(|g| g.route_id == stop.route_id && g.direction_id == stop.direction_id)
         {
-            if let Ok(text) = resp.text().await {
-                if let Ok(parsed) = serde_json::from_str::<serde_json::Value>(&text) {
-                    if let Some(data) = parsed.get("data").and_then(|d| d.as_array()) {
-                        for item in data {
-                            let id = item.get("id").and_then(|v| v.as_str()).unwrap_or("");
-                            let parent_id = item.get("relationships")
-                                .and_then(|r| r.get("parent_station"))
-                                .and_then(|ps| ps.get("data"))
-                                .and_then(|d| d.get("id"))
-                                .and_then(|v| v.as_str())
-                                .unwrap_or(id);
-                            stop_parent_map.insert(id.to_string(), parent_id.to_string());
-                        }
-                    }
-                }
+            Some(group) => group.stops.push(stop),
+            None => groups.push(RouteGroup { route_id: stop.route_id, direction_id: stop.direction_id, stops: vec![stop] }),
+        }
+    }
+
+    // Fetched predictions are for the real time, so a simulated clock drops them
+    let simulated = replay::simulated();
+    let pred_futures = provider_groups.iter().map(|(name, groups)| async move {
+        match providers.get(name) {
+            Ok(provider) if simulated && provider.live_predictions() => {
+                groups.iter().map(|_| Ok(HashMap::new())).collect()
             }
+            Ok(provider) => provider.predictions(groups).await,
+            Err(e) => groups.iter().map(|_| Err(e.clone().into())).collect(),
+        }
+    });
+    let sched_futures = stops.iter().map(|stop| async move {
+        providers.get(stop.provider)?.schedules(stop, window.for_stop(stop), now).await
+    });
+    let (pred_results, sched_results) = tokio::join!(join_all(pred_futures), join_all(sched_futures));
+
+    // Index each route group's predictions (or its error) by (provider, route, direction)
+    let mut route_preds: HashMap<(&str, &str, i32), Result<RoutePredictions, String>> = HashMap::new();
+    for ((name, groups), results) in provider_groups.iter().zip(pred_results) {
+        for (group, res) in groups.iter().zip(results) {
+            route_preds.insert((name, group.route_id, group.direction_id), res.map_err(|e| e.to_string()));
         }
     }
-    let to_parent = |id: &str| -> String {
-        stop_parent_map.get(id).cloned().unwrap_or_else(|| id.to_string())
-    };
 
-    // Fetch route stops list for counting stops between vehicle and target
-    let route_stop_ids: Vec<String> = {
-        let route_stops_url = format!("{}/stops", BASE_URL);
-        let route_stops_params = [
-            ("filter[route]", stop.route_id.to_string()),
-            ("filter[direction_id]", stop.direction_id.to_string()),
-        ];
-        match client
-            .get(&route_stops_url)
-            .header("accept", "application/vnd.api+json")
-            .query(&route_stops_params)
-            .send()
-            .await
-        {
-            Ok(resp) if resp.status().is_success() => {
-                match resp.text().await {
-                    Ok(text) => serde_json::from_str::<RouteStopsResponse>(&text)
-                        .map(|r| r.data.into_iter().map(|s| s.id).collect())
-                        .unwrap_or_default(),
-                    Err(_) => vec![],
-                }
+    stops
+        .iter()
+        .zip(sched_results)
+        .map(|(stop, sched)| {
+            let sched = sched?;
+            match &route_preds[&(stop.provider, stop.route_id, stop.direction_id)] {
+                Ok(preds) => Ok(merge_schedule_and_predictions(stop, sched, preds.get(stop.stop_id), now)),
+                Err(e) => Err(e.clone().into()),
             }
-            _ => vec![],
+        })
+        .collect()
+}
+
+/// Fetches elevator and escalator outages for every stop from its provider.
+/// Failures are reported and leave the stops without outages.
+async fn fetch_outages(
+    providers: &Providers,
+    stops: &[StopConfig],
+    err_color: bool,
+    now: DateTime<Local>,
+) -> StopOutages {
+    let mut by_provider: Vec<(&str, Vec<&StopConfig>)> = Vec::new();
+    for stop in stops {
+        match by_provider.iter_mut().find(|(name, _)| *name == stop.provider) {
+            Some((_, stops)) => stops.push(stop),
+            None => by_provider.push((stop.provider, vec![stop])),
         }
-    };
+    }
 
-    // Map predictions by trip_id, with vehicle and stop info
-    struct PredInfo {
-        attrs: PredictionAttributes,
-        vehicle_stop: Option<String>,
-        pred_stop: Option<String>,
-    }
-    let mut predictions_map: HashMap<String, PredInfo> = HashMap::new();
-    for p in pred_resp.data {
-        let vehicle_current_stop = p.relationships.vehicle
-            .as_ref()
-            .and_then(|v| v.data.as_ref())
-            .and_then(|d| vehicle_stop_ids.get(&d.id).cloned());
-        let pred_stop = p.relationships.stop
-            .as_ref()
-            .map(|s| s.data.id.clone());
-        predictions_map.insert(p.relationships.trip.data.id, PredInfo {
-            attrs: p.attributes,
-            vehicle_stop: vehicle_current_stop,
-            pred_stop,
-        });
+    let futures = by_provider.iter().map(|(name, stops)| async move {
+        providers.get(name)?.outages(stops, now).await.map_err(|e| e.to_string())
+    });
+    let mut outages = HashMap::new();
+    for result in join_all(futures).await {
+        match result {
+            Ok(found) => outages.extend(found),
+            // The departures report rate limiting themselves
+            Err(e) if e == "Rate limited" => {}
+            Err(e) => {
+                let msg = format!("⚠️  Error fetching elevator and escalator status: {}", e);
+                eprintln!("{}", paint(&msg, STYLE_ERROR, err_color));
+            }
+        }
     }
+    outages
+}
 
+/// Joins a stop's schedules with its live predictions by trip_id.
+fn merge_schedule_and_predictions(
+    stop: &StopConfig,
+    schedules: Vec<ScheduledTrip>,
+    predictions: Option<&HashMap<String, StopPrediction>>,
+    now: DateTime<Local>,
+) -> Vec<RowData> {
     let mut results = Vec::new();
 
-    for s in sched_resp.data {
-        let trip_id = s.relationships.trip.data.id;
+    for s in schedules {
+        let trip_id = s.trip_id;
 
-        let sched_time_str = if stop.is_origin {
-            s.attributes.departure_time
+        let sched_dt = if stop.is_origin {
+            s.departure
         } else {
-            s.attributes.arrival_time.or(s.attributes.departure_time)
+            s.arrival.or(s.departure)
         };
 
-        let sched_dt = parse_time(sched_time_str);
-
-        let pred_entry = predictions_map.get(&trip_id);
+        let pred_entry = predictions.and_then(|p| p.get(&trip_id));
         let (pred_dt, stops_away) = if let Some(info) = pred_entry {
-            let pred_time_str = if stop.is_origin {
-                info.attrs.departure_time.clone()
+            let pred_dt = if stop.is_origin {
+                info.departure
             } else {
-                info.attrs.arrival_time.clone().or(info.attrs.departure_time.clone())
-            };
-            let dt = parse_time(pred_time_str);
-            // Count actual stops between vehicle and target using route stops list
-            let sa = match (&info.vehicle_stop, &info.pred_stop) {
-                (Some(v_stop), Some(t_stop)) if !route_stop_ids.is_empty() => {
-                    let v_parent = to_parent(v_stop);
-                    let t_parent = to_parent(t_stop);
-                    let v_idx = route_stop_ids.iter().position(|id| *id == v_parent);
-                    let t_idx = route_stop_ids.iter().position(|id| *id == t_parent);
-                    match (v_idx, t_idx) {
-                        (Some(vi), Some(ti)) => {
-                            let diff = (ti as i32 - vi as i32).unsigned_abs() as i32;
-                            if diff > 0 && diff <= 20 { Some(diff) } else { None }
-                        }
-                        _ => None,
-                    }
-                }
-                _ => None,
+                info.arrival.or(info.departure)
             };
-            (dt, sa)
+            (pred_dt, info.stops_away)
         } else {
             (None, None)
         };
 
-        results.push(RowData { sched_dt, pred_dt, stops_away });
+        results.push(RowData {
+            trip_id,
+            headsign: s.headsign,
+            sched_dt,
+            pred_dt,
+            stops_away,
+            wheelchair_accessible: s.wheelchair_accessible,
+        });
     }
 
     // Sort by time (use prediction if available, otherwise scheduled)
@@ -493,7 +770,39 @@ async fn get_schedule_and_predictions(
             .unwrap_or_else(|| now + Duration::days(1))
     });
 
-    Ok(results)
+    results
+}
+
+/// Drops trips that left more than `past_cutoff_minutes` ago or depart beyond the
+/// horizon, past schedule-only trips when live data exists, and inaccessible
+/// trips with `accessible_only`.
+fn filter_rows(rows: Vec<RowData>, window: &WindowSettings, now: DateTime<Local>) -> Vec<RowData> {
+    let filtered: Vec<RowData> = rows.into_iter()
+        .filter(|r| !(window.accessible_only && r.wheelchair_accessible == Some(false)))
+        .filter(|r| {
+            let s_diff = r
+                .sched_dt
+                .map(|t| t.signed_duration_since(now).num_minutes())
+                .unwrap_or(0);
+            let p_diff = r
+                .pred_dt
+                .map(|t| t.signed_duration_since(now).num_minutes())
+                .unwrap_or(s_diff);
+            s_diff > -window.past_cutoff_minutes || p_diff > -window.past_cutoff_minutes
+        })
+        .filter(|r| match window.horizon_minutes {
+            Some(h) => r.pred_dt.or(r.sched_dt).is_some_and(|t| t <= now + Duration::minutes(h)),
+            None => true,
+        })
+        .collect();
+    let has_live = filtered.iter().any(|r| r.pred_dt.is_some());
+    if has_live {
+        filtered.into_iter()
+            .filter(|r| r.pred_dt.is_some() || r.sched_dt.map(|t| t > now).unwrap_or(false))
+            .collect()
+    } else {
+        filtered
+    }
 }
 
 fn parse_time(time_str: Option<String>) -> Option<DateTime<Local>> {
@@ -505,6 +814,16 @@ fn parse_time(time_str: Option<String>) -> Option<DateTime<Local>> {
     None
 }
 
+/// Reads a GTFS `wheelchair_accessible` value: 1 is accessible, 2 is not, and
+/// anything else (0 included) is unknown.
+fn wheelchair_accessible(value: i64) -> Option<bool> {
+    match value {
+        1 => Some(true),
+        2 => Some(false),
+        _ => None,
+    }
+}
+
 fn format_time_compact(dt: DateTime<Local>, now: DateTime<Local>) -> String {
     let time_str = dt.format("%H:%M").to_string();
     let diff = dt.signed_duration_since(now).num_minutes();
@@ -533,13 +852,84 @@ fn format_time_compact_with_seconds(dt: DateTime<Local>, now: DateTime<Local>, i
     }
 }
 
+/// Formats a delay as "on time", "+4m late" or "2m early".
+fn format_delay(delay_secs: i64) -> String {
+    let minutes = (delay_secs as f64 / 60.0).round() as i64;
+    match DelayStatus::from_delay(delay_secs) {
+        DelayStatus::OnTime => "on time".to_string(),
+        DelayStatus::Early => format!("{}m early", minutes.abs()),
+        DelayStatus::Late | DelayStatus::VeryLate => format!("+{}m late", minutes),
+    }
+}
+
+/// Wraps `s` in an ANSI SGR sequence when colors are enabled.
+fn paint(s: &str, code: &str, color: bool) -> String {
+    if color {
+        format!("\x1b[{}m{}\x1b[0m", code, s)
+    } else {
+        s.to_string()
+    }
+}
+
+/// Width of the terminal attached to stdout, falling back to `$COLUMNS`.
+fn terminal_width() -> Option<usize> {
+    terminal_size::terminal_size()
+        .map(|(terminal_size::Width(w), _)| w as usize)
+        .or_else(|| std::env::var("COLUMNS").ok().and_then(|c| c.parse().ok()))
+}
+
+/// Removes ANSI escape sequences, which take no space on screen.
+fn strip_ansi(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            for c in chars.by_ref() {
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+/// Terminal columns taken by `s`, using East Asian Width per grapheme cluster.
 fn display_width(s: &str) -> usize {
-    s.chars().map(|c| {
-        match c {
-            '🟢' | '📅' => 2,
-            _ => 1,
+    strip_ansi(s).graphemes(true).map(|g| g.width()).sum()
+}
+
+/// Cuts `s` to at most `max_width` columns, ending in "…" when anything was dropped.
+fn truncate_to_width(s: &str, max_width: usize) -> String {
+    if display_width(s) <= max_width {
+        return s.to_string();
+    }
+
+    let mut out = String::new();
+    let mut width = 0;
+    let mut in_escape = false;
+    for g in s.graphemes(true) {
+        if in_escape || g == "\x1b" {
+            // Keep styling intact; escape sequences are zero-width
+            out.push_str(g);
+            in_escape = g == "\x1b" || !g.chars().all(|c| c.is_ascii_alphabetic());
+            continue;
         }
-    }).sum()
+        let w = g.width();
+        // Leave one column for the ellipsis
+        if width + w + 1 > max_width {
+            break;
+        }
+        out.push_str(g);
+        width += w;
+    }
+    out.push('…');
+    if s.contains('\x1b') {
+        out.push_str("\x1b[0m");
+    }
+    out
 }
 
 fn pad_to_width(s: &str, target_width: usize) -> String {
@@ -556,11 +946,23 @@ struct StopDisplay {
     times: Vec<String>,
 }
 
-fn format_stop_data(stop_name: &str, rows: &[RowData], now: DateTime<Local>) -> StopDisplay {
+/// Formats a stop's trips for display. With `walk_minutes`, each trip also says
+/// when to leave, and trips that can no longer be reached are hidden except the
+/// most recent one, which is marked "missed".
+fn format_stop_data(
+    stop_name: &str,
+    rows: &[RowData],
+    window: &WindowSettings,
+    walk_minutes: Option<i64>,
+    confidence: Option<&accuracy::Accuracy>,
+    display: &DisplayOptions,
+    now: DateTime<Local>,
+) -> StopDisplay {
     let mut times = Vec::new();
+    let no_trips = paint("No upcoming trips", STYLE_DIM, display.color);
 
     if rows.is_empty() {
-        times.push("No upcoming trips".to_string());
+        times.push(no_trips);
         return StopDisplay {
             name: stop_name.to_string(),
             times,
@@ -570,33 +972,87 @@ fn format_stop_data(stop_name: &str, rows: &[RowData], now: DateTime<Local>) ->
     let mut count = 0;
     let first_live_index = rows.iter().position(|r| r.pred_dt.is_some());
 
+    // Seconds until you must leave for a trip, when walking time is known
+    let leave_in = |row: &RowData| -> Option<i64> {
+        let t = row.pred_dt.or(row.sched_dt)?;
+        Some((t - Duration::minutes(walk_minutes?)).signed_duration_since(now).num_seconds())
+    };
+    let last_missed_index = rows.iter().rposition(|r| leave_in(r).is_some_and(|s| s < 0));
+    let headway_events = display
+        .bunch_minutes
+        .map(|minutes| headway::detect(rows, minutes))
+        .unwrap_or_default();
+
     for (idx, row) in rows.iter().enumerate() {
-        if count >= 3 {
+        if count >= window.trips {
             break;
         }
 
+        let missed = leave_in(row).is_some_and(|s| s < 0);
+        if missed && Some(idx) != last_missed_index {
+            continue;
+        }
+
         let time_str = match (row.sched_dt, row.pred_dt) {
             (_, Some(pred)) => {
                 // Include seconds only for first live departure
                 let include_seconds = first_live_index == Some(idx);
-                let base = format!("🟢 {}", format_time_compact_with_seconds(pred, now, include_seconds));
+                let time = format_time_compact_with_seconds(pred, now, include_seconds);
+                // Departed trips kept by --past are not imminent
+                let imminent = (0..IMMINENT_MINUTES * 60).contains(&pred.signed_duration_since(now).num_seconds());
+                let time = if imminent { paint(&time, STYLE_IMMINENT, display.color) } else { time };
+                let mut base = format!("🟢 {}", time);
+                if let Some(delay) = row.delay_seconds() {
+                    let status = DelayStatus::from_delay(delay);
+                    base = format!("{} {}", base, paint(&format_delay(delay), status.color(), display.color));
+                }
+                // Typical error of predictions this far out, e.g. "-1/+3m"
+                if let Some((low, high)) = confidence.and_then(|a| a.range(pred.signed_duration_since(now).num_seconds())) {
+                    let (low, high) = ((low as f64 / 60.0).round(), (high as f64 / 60.0).round());
+                    let range = format!("{:+}/{:+}m", low, high);
+                    base = format!("{} {}", base, paint(&range, STYLE_DIM, display.color));
+                }
                 match row.stops_away {
                     Some(n) if n > 0 => format!("{} ({} stop{})", base, n, if n == 1 { "" } else { "s" }),
                     _ => base,
                 }
             }
             (Some(sched), None) => {
-                format!("📅 {}", format_time_compact(sched, now))
+                paint(&format!("📅 {}", format_time_compact(sched, now)), STYLE_DIM, display.color)
             }
             (None, None) => continue,
         };
 
+        let mut time_str = time_str;
+        for event in &headway_events {
+            let note = match event {
+                headway::HeadwayEvent::Bunched { trip_id, .. } if *trip_id == row.trip_id => {
+                    "bunched with next bus".to_string()
+                }
+                headway::HeadwayEvent::Gap { trip_id, gap_secs, .. } if *trip_id == row.trip_id => {
+                    format!("after {}m gap", gap_secs / 60)
+                }
+                _ => continue,
+            };
+            time_str = format!("{} · {}", time_str, paint(&note, STYLE_HEADWAY, display.color));
+        }
+
+        let time_str = match leave_in(row) {
+            Some(secs) if secs < 0 => format!("{} · {}", time_str, paint("missed", STYLE_DIM, display.color)),
+            Some(secs) if secs < 60 => format!("{} · {}", time_str, paint("leave now", STYLE_IMMINENT, display.color)),
+            Some(secs) => format!("{} · leave in {}m", time_str, secs / 60),
+            None => time_str,
+        };
+
         times.push(time_str);
-        count += 1;
+        // The missed trip is shown in addition to the catchable ones
+        if !missed {
+            count += 1;
+        }
     }
 
     if times.is_empty() {
-        times.push("No upcoming trips".to_string());
+        times.push(no_trips);
     }
 
     StopDisplay {
@@ -605,12 +1061,83 @@ fn format_stop_data(stop_name: &str, rows: &[RowData], now: DateTime<Local>) ->
     }
 }
 
-fn print_stops_grid(title: &str, stops: Vec<StopDisplay>) {
-    println!("{}", title);
+/// Machine-readable view of the trips `format_stop_data` would show for a stop.
+fn stop_json(stop: &StopConfig, rows: &[RowData], window: &WindowSettings) -> serde_json::Value {
+    let trips: Vec<serde_json::Value> = rows
+        .iter()
+        .filter(|r| r.sched_dt.is_some() || r.pred_dt.is_some())
+        .take(window.trips)
+        .map(|r| {
+            let delay = r.delay_seconds();
+            serde_json::json!({
+                "trip_id": r.trip_id,
+                "headsign": r.headsign,
+                "scheduled_time": r.sched_dt.map(|t| t.to_rfc3339()),
+                "predicted_time": r.pred_dt.map(|t| t.to_rfc3339()),
+                "stops_away": r.stops_away,
+                "delay_seconds": delay,
+                "status": delay.map(|d| DelayStatus::from_delay(d).as_str()),
+            })
+        })
+        .collect();
+
+    serde_json::json!({
+        "name": stop.name,
+        "route_id": stop.route_id,
+        "stop_id": stop.stop_id,
+        "direction_id": stop.direction_id,
+        "trips": trips,
+    })
+}
+
+/// Prints a group of stops under `title` in the configured layout.
+fn print_stops(title: &str, title_style: &str, stops: Vec<StopDisplay>, display: &DisplayOptions) {
+    println!("{}", paint(title, title_style, display.color));
 
+    let layout = match display.layout {
+        cli::Layout::Auto if display.width.is_some_and(|w| w < NARROW_WIDTH) => cli::Layout::List,
+        cli::Layout::Auto => cli::Layout::Grid,
+        layout => layout,
+    };
+    match layout {
+        cli::Layout::List => print_stops_list(&stops, display),
+        cli::Layout::Compact => print_stops_compact(&stops, display),
+        _ => print_stops_grid(&stops, display),
+    }
+
+    println!();
+}
+
+/// Stops side by side, reflowed into several rows of columns when the
+/// terminal is too narrow to fit them all.
+fn print_stops_grid(stops: &[StopDisplay], display: &DisplayOptions) {
+    // Widen columns when an annotated time would not fit, up to a limit past
+    // which cells are truncated
+    let mut col_width = stops
+        .iter()
+        .flat_map(|s| s.times.iter().map(|t| display_width(t)))
+        .max()
+        .unwrap_or(0)
+        .clamp(MIN_COL_WIDTH, MAX_COL_WIDTH);
+    let columns = match display.width {
+        Some(width) => {
+            col_width = col_width.min(width);
+            ((width + 2) / (col_width + 2)).max(1)
+        }
+        None => stops.len().max(1),
+    };
+
+    for (row_idx, row) in stops.chunks(columns).enumerate() {
+        if row_idx > 0 {
+            println!();
+        }
+        print_grid_row(row, col_width, display);
+    }
+}
+
+fn print_grid_row(stops: &[StopDisplay], col_width: usize, display: &DisplayOptions) {
     // Find max times count
     let max_times = stops.iter().map(|s| s.times.len()).max().unwrap_or(0);
-    let col_width = 32;
 
     // Pre-compute wrapped names for all stops
     let wrapped_names: Vec<Vec<String>> = stops.iter().map(|stop| {
@@ -619,14 +1146,18 @@ fn print_stops_grid(title: &str, stops: Vec<StopDisplay>) {
         let mut lines = Vec::new();
 
         for part in name_parts {
-            if current_line.len() + part.len() + 1 <= col_width {
+            // A single word wider than the column is truncated
+            let part = truncate_to_width(part, col_width);
+            if display_width(&current_line) + display_width(&part) < col_width {
                 if !current_line.is_empty() {
                     current_line.push(' ');
                 }
-                current_line.push_str(part);
+                current_line.push_str(&part);
             } else {
-                lines.push(current_line.clone());
-                current_line = part.to_string();
+                if !current_line.is_empty() {
+                    lines.push(current_line.clone());
+                }
+                current_line = part;
             }
         }
         if !current_line.is_empty() {
@@ -647,23 +1178,41 @@ fn print_stops_grid(title: &str, stops: Vec<StopDisplay>) {
                 ""
             };
 
-            print!("{}  ", pad_to_width(line_text, col_width));
+            print!("{}  ", pad_to_width(&paint(line_text, STYLE_BOLD, display.color), col_width));
         }
         println!();
     }
 
     // Print times
     for time_idx in 0..max_times {
-        for stop in &stops {
+        for stop in stops {
             let time_text = if time_idx < stop.times.len() {
                 &stop.times[time_idx]
             } else {
                 ""
             };
-            print!("{}  ", pad_to_width(time_text, col_width));
+            print!("{}  ", pad_to_width(&truncate_to_width(time_text, col_width), col_width));
         }
         println!();
     }
+}
 
-    println!();
+/// One stop after another, each name followed by its indented times.
+fn print_stops_list(stops: &[StopDisplay], display: &DisplayOptions) {
+    let width = display.width.unwrap_or(usize::MAX);
+    for stop in stops {
+        println!("{}", paint(&truncate_to_width(&stop.name, width), STYLE_BOLD, display.color));
+        for time in &stop.times {
+            println!("  {}", truncate_to_width(time, width.saturating_sub(2)));
+        }
+    }
+}
+
+/// One line per stop: "name: time · time · time".
+fn print_stops_compact(stops: &[StopDisplay], display: &DisplayOptions) {
+    let width = display.width.unwrap_or(usize::MAX);
+    for stop in stops {
+        let line = format!("{}: {}", paint(&stop.name, STYLE_BOLD, display.color), stop.times.join(" · "));
+        println!("{}", truncate_to_width(&line, width));
+    }
 }
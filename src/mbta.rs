@@ -0,0 +1,628 @@
+//! The MBTA V3 JSON:API provider. Predictions are fetched per route/direction
+//! and matched to monitored stops through their parent stations.
+
+use chrono::{DateTime, Duration, Local, NaiveDate, Timelike};
+use futures::future::{join_all, LocalBoxFuture};
+use reqwest::Client;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::error::Error;
+
+use crate::gtfs::{self, GtfsStore};
+use crate::provider::{Provider, RouteGroup};
+use crate::{
+    gtfs_rt, metrics, parse_time, replay, wheelchair_accessible, Outage, RoutePredictions, ScheduledTrip, StopConfig, StopOutages,
+    StopPrediction, WindowSettings,
+    SCHEDULE_WINDOW_MINUTES,
+};
+
+const BASE_URL: &str = "https://api-v3.mbta.com";
+/// Page size requested from paginated endpoints; `links.next` is followed for the rest.
+const PAGE_LIMIT: usize = 50;
+/// Upper bound on pages followed for one request, guarding against runaway pagination.
+const MAX_PAGES: usize = 10;
+/// MBTA service days run past midnight; trips before this hour belong to the previous day.
+pub const SERVICE_DAY_END_HOUR: u32 = 3;
+
+#[derive(Debug, Deserialize)]
+pub struct ApiResponse<T> {
+    pub data: Vec<T>,
+    #[serde(default)]
+    pub included: Vec<IncludedResource>,
+    #[serde(default)]
+    links: Option<Links>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Links {
+    next: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Resource<A, R> {
+    pub id: String,
+    pub attributes: A,
+    pub relationships: R,
+}
+
+#[derive(Debug, Deserialize)]
+struct ScheduleAttributes {
+    arrival_time: Option<String>,
+    departure_time: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ScheduleRelationships {
+    trip: DataWrapper,
+}
+
+#[derive(Debug, Deserialize)]
+struct PredictionAttributes {
+    arrival_time: Option<String>,
+    departure_time: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PredictionRelationships {
+    trip: DataWrapper,
+    vehicle: Option<OptionalDataWrapper>,
+    stop: Option<DataWrapper>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FacilityAttributes {
+    #[serde(rename = "type")]
+    facility_type: String,
+    short_name: Option<String>,
+    long_name: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FacilityRelationships {
+    stop: OptionalDataWrapper,
+}
+
+#[derive(Debug, Deserialize)]
+struct AlertAttributes {
+    effect: String,
+    #[serde(default)]
+    informed_entity: Vec<InformedEntity>,
+}
+
+#[derive(Debug, Deserialize)]
+struct InformedEntity {
+    facility: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct OptionalDataWrapper {
+    pub data: Option<IdWrapper>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct IncludedResource {
+    #[serde(rename = "type")]
+    resource_type: String,
+    id: String,
+    #[serde(default)]
+    attributes: serde_json::Value,
+    #[serde(default)]
+    relationships: serde_json::Value,
+}
+
+#[derive(Debug, Deserialize)]
+struct RouteStopsResponse {
+    data: Vec<RouteStop>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RouteStop {
+    id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct DataWrapper {
+    data: IdWrapper,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct IdWrapper {
+    pub id: String,
+}
+
+/// An elevator or escalator at a station.
+#[derive(Clone)]
+struct Facility {
+    id: String,
+    kind: &'static str,
+    name: String,
+}
+
+/// The station a monitored stop belongs to and its elevators and escalators.
+#[derive(Clone)]
+struct StationFacilities {
+    station: String,
+    facilities: Vec<Facility>,
+}
+
+/// The MBTA V3 API, reading schedules from a GTFS feed (`--gtfs`) and
+/// predictions from GTFS-realtime feeds (`--gtfs-rt`) instead when given.
+pub struct MbtaV3 {
+    client: Client,
+    gtfs: Option<GtfsStore>,
+    realtime: Vec<String>,
+    /// stop_id -> its station's facilities, which rarely change, so they are
+    /// fetched once per stop rather than every refresh
+    facilities: RefCell<HashMap<String, StationFacilities>>,
+}
+
+impl MbtaV3 {
+    pub fn new(client: Client, gtfs: Option<GtfsStore>, realtime: Vec<String>) -> MbtaV3 {
+        MbtaV3 { client, gtfs, realtime, facilities: RefCell::new(HashMap::new()) }
+    }
+
+    /// Looks up the station and facilities of any of `stops` not seen before.
+    /// Stops whose station could not be looked up are not cached, so they are
+    /// tried again on the next refresh rather than never showing outages.
+    async fn load_facilities(&self, stops: &[&StopConfig]) -> Result<(), Box<dyn Error>> {
+        let missing: Vec<String> = {
+            let known = self.facilities.borrow();
+            stops.iter().filter(|s| !known.contains_key(s.stop_id)).map(|s| s.stop_id.to_string()).collect()
+        };
+        if missing.is_empty() {
+            return Ok(());
+        }
+
+        let mut stop_parent_map: HashMap<String, String> = HashMap::new();
+        resolve_parents(&self.client, &missing, &mut stop_parent_map).await;
+        let mut stations: Vec<&str> =
+            missing.iter().filter_map(|id| stop_parent_map.get(id)).map(String::as_str).collect();
+        if stations.is_empty() {
+            return Ok(());
+        }
+        stations.sort();
+        stations.dedup();
+
+        let facility_params = [
+            ("filter[stop]", stations.join(",")),
+            ("filter[type]", "ELEVATOR,ESCALATOR".to_string()),
+        ];
+        let resp: ApiResponse<Resource<FacilityAttributes, FacilityRelationships>> =
+            api_get_all(&self.client, "/facilities", &facility_params, "Facilities").await?;
+        let mut by_station: HashMap<String, Vec<Facility>> = HashMap::new();
+        for f in resp.data {
+            let Some(station) = f.relationships.stop.data.map(|d| d.id) else { continue };
+            let kind = if f.attributes.facility_type == "ELEVATOR" { "Elevator" } else { "Escalator" };
+            let name = f.attributes.short_name.or(f.attributes.long_name).unwrap_or_else(|| f.id.clone());
+            by_station.entry(station).or_default().push(Facility { id: f.id, kind, name });
+        }
+
+        let mut known = self.facilities.borrow_mut();
+        for stop_id in missing {
+            let Some(station) = stop_parent_map.get(&stop_id).cloned() else { continue };
+            let facilities = by_station.get(&station).cloned().unwrap_or_default();
+            known.insert(stop_id, StationFacilities { station, facilities });
+        }
+        Ok(())
+    }
+
+    /// Closures from the alerts for wheelchair and escalator users active at
+    /// `now`, matched to the facilities of each stop's station.
+    async fn get_outages(&self, stops: &[&StopConfig], now: DateTime<Local>) -> Result<StopOutages, Box<dyn Error>> {
+        self.load_facilities(stops).await?;
+        let known: HashMap<String, StationFacilities> = {
+            let cache = self.facilities.borrow();
+            stops.iter().filter_map(|s| Some((s.stop_id.to_string(), cache.get(s.stop_id)?.clone()))).collect()
+        };
+        let mut stations: Vec<&str> = known
+            .values()
+            .filter(|s| !s.facilities.is_empty())
+            .map(|s| s.station.as_str())
+            .collect();
+        if stations.is_empty() {
+            // Nothing to break down, as at most bus stops
+            return Ok(HashMap::new());
+        }
+        stations.sort();
+        stations.dedup();
+
+        let alert_params = [
+            ("filter[stop]", stations.join(",")),
+            ("filter[activity]", "USING_WHEELCHAIR,USING_ESCALATOR".to_string()),
+            ("filter[datetime]", now.format("%Y-%m-%dT%H:%M:%S%:z").to_string()),
+        ];
+        let alerts: ApiResponse<Resource<AlertAttributes, Option<serde_json::Value>>> =
+            api_get_all(&self.client, "/alerts", &alert_params, "Alerts").await?;
+        let closed: std::collections::HashSet<&str> = alerts
+            .data
+            .iter()
+            .filter(|a| a.attributes.effect == "ELEVATOR_CLOSURE" || a.attributes.effect == "ESCALATOR_CLOSURE")
+            .flat_map(|a| a.attributes.informed_entity.iter().filter_map(|e| e.facility.as_deref()))
+            .collect();
+
+        Ok(known
+            .into_iter()
+            .map(|(stop_id, station)| {
+                let outages: Vec<Outage> = station
+                    .facilities
+                    .into_iter()
+                    .filter(|f| closed.contains(f.id.as_str()))
+                    .map(|f| Outage { kind: f.kind, facility: f.name })
+                    .collect();
+                (stop_id, outages)
+            })
+            .filter(|(_, outages)| !outages.is_empty())
+            .collect())
+    }
+}
+
+impl Provider for MbtaV3 {
+    fn schedules<'a>(
+        &'a self,
+        stop: &'a StopConfig,
+        window: WindowSettings,
+        now: DateTime<Local>,
+    ) -> LocalBoxFuture<'a, Result<Vec<ScheduledTrip>, Box<dyn Error>>> {
+        Box::pin(async move {
+            match &self.gtfs {
+                Some(gtfs) => gtfs.schedules(stop, window, now),
+                None => get_schedules(&self.client, stop, window, now).await,
+            }
+        })
+    }
+
+    /// Live unless every `--gtfs-rt` source is a saved file.
+    fn live_predictions(&self) -> bool {
+        self.realtime.is_empty() || self.realtime.iter().any(|s| gtfs_rt::is_url(s))
+    }
+
+    /// One `/predictions` request per route/direction, with a comma-separated
+    /// `filter[stop]` fanned back out to each stop.
+    fn predictions<'a>(
+        &'a self,
+        groups: &'a [RouteGroup<'a>],
+    ) -> LocalBoxFuture<'a, Vec<Result<RoutePredictions, Box<dyn Error>>>> {
+        Box::pin(async move {
+            // `--gtfs-rt` needs `--gtfs`
+            if let Some(gtfs) = self.gtfs.as_ref().filter(|_| !self.realtime.is_empty()) {
+                return gtfs_rt::group_predictions(&self.client, &self.realtime, gtfs, groups).await;
+            }
+            let futures = groups
+                .iter()
+                .map(|g| get_route_predictions(&self.client, g.route_id, g.direction_id, &g.stops));
+            join_all(futures).await
+        })
+    }
+
+    fn outages<'a>(
+        &'a self,
+        stops: &'a [&'a StopConfig],
+        now: DateTime<Local>,
+    ) -> LocalBoxFuture<'a, Result<StopOutages, Box<dyn Error>>> {
+        Box::pin(self.get_outages(stops, now))
+    }
+}
+
+/// Issues a GET against the MBTA API, mapping HTTP 429 to a "Rate limited" error.
+async fn api_get(client: &Client, path: &str, params: &[(&str, String)]) -> Result<String, Box<dyn Error>> {
+    api_get_url(client, &format!("{}{}", BASE_URL, path), params).await
+}
+
+/// Issues a GET, or answers it from the recording when replaying. Responses are
+/// saved when recording.
+async fn api_get_url(client: &Client, url: &str, params: &[(&str, String)]) -> Result<String, Box<dyn Error>> {
+    if let Some(replayed) = replay::replayed(url, params) {
+        let (status, body) = replayed?;
+        if status == 429 {
+            return Err("Rate limited".into());
+        }
+        return Ok(body);
+    }
+
+    // Counted per endpoint path, without the query string
+    let endpoint = reqwest::Url::parse(url).map_or_else(|_| url.to_string(), |u| u.path().to_string());
+    let resp = match client
+        .get(url)
+        .header("accept", "application/vnd.api+json")
+        .query(params)
+        .send()
+        .await
+    {
+        Ok(resp) => resp,
+        Err(e) => {
+            metrics::record_request(&endpoint, None);
+            return Err(e.into());
+        }
+    };
+    let status = resp.status().as_u16();
+    metrics::record_request(&endpoint, Some(status));
+    let text = resp.text().await?;
+    replay::save(url, params, status, &text);
+
+    // Check for rate limiting
+    if status == 429 {
+        return Err("Rate limited".into());
+    }
+
+    Ok(text)
+}
+
+/// Fetches every page of a JSON:API collection by following `links.next`,
+/// concatenating `data` and `included` across pages.
+pub async fn api_get_all<T: DeserializeOwned>(
+    client: &Client,
+    path: &str,
+    params: &[(&str, String)],
+    label: &str,
+) -> Result<ApiResponse<T>, Box<dyn Error>> {
+    let mut params = params.to_vec();
+    params.push(("page[limit]", PAGE_LIMIT.to_string()));
+
+    let mut combined = ApiResponse { data: Vec::new(), included: Vec::new(), links: None };
+    let mut next_url = Some(format!("{}{}", BASE_URL, path));
+    let mut pages = 0;
+    while let Some(url) = next_url.take() {
+        // `links.next` already carries the query string of the original request
+        let query: &[(&str, String)] = if pages == 0 { &params } else { &[] };
+        let text = api_get_url(client, &url, query).await?;
+
+        let page: ApiResponse<T> = match serde_json::from_str(&text) {
+            Ok(v) => v,
+            Err(e) => {
+                metrics::record_parse_failure(label);
+                eprintln!("Failed to parse {} JSON: {}", label, e);
+                eprintln!("Raw Body: {}", text);
+                return Err(Box::new(e));
+            }
+        };
+
+        combined.data.extend(page.data);
+        combined.included.extend(page.included);
+        pages += 1;
+        next_url = page.links.and_then(|l| l.next);
+        if pages >= MAX_PAGES && next_url.is_some() {
+            eprintln!(
+                "⚠️  {} results truncated after {} pages of {}; later trips are missing",
+                label, MAX_PAGES, PAGE_LIMIT
+            );
+            break;
+        }
+    }
+
+    Ok(combined)
+}
+
+async fn get_schedules(
+    client: &Client,
+    stop: &StopConfig,
+    window: WindowSettings,
+    now: DateTime<Local>,
+) -> Result<Vec<ScheduledTrip>, Box<dyn Error>> {
+    // Look back to catch delayed trips, and look ahead far enough that every
+    // trip in the window is matched against its prediction
+    let lookback_time = now - Duration::minutes(window.lookback_minutes);
+    let lookahead_time = now + Duration::minutes(window.horizon_minutes.unwrap_or(SCHEDULE_WINDOW_MINUTES));
+
+    // Service dates are explicit so that `--at` can look at any day. A window
+    // crossing into the next service day (e.g. at 02:45) queries both days.
+    let mut scheduled = Vec::new();
+    let mut date = service_date(lookback_time);
+    while date <= service_date(lookahead_time) {
+        let day_start = gtfs::service_day_start(date, &Local).ok_or("invalid service date")?;
+        let sched_params = [
+            ("filter[stop]", stop.stop_id.to_string()),
+            ("filter[route]", stop.route_id.to_string()),
+            ("filter[direction_id]", stop.direction_id.to_string()),
+            ("sort", "arrival_time".to_string()),
+            ("filter[date]", date.format("%Y-%m-%d").to_string()),
+            ("filter[min_time]", service_time(lookback_time, day_start)),
+            ("filter[max_time]", service_time(lookahead_time, day_start)),
+            ("include", "trip".to_string()),
+        ];
+
+        let schedules: ApiResponse<Resource<ScheduleAttributes, ScheduleRelationships>> =
+            api_get_all(client, "/schedules", &sched_params, "Sched").await?;
+
+        let trips: HashMap<&str, &IncludedResource> = schedules
+            .included
+            .iter()
+            .filter(|inc| inc.resource_type == "trip")
+            .map(|inc| (inc.id.as_str(), inc))
+            .collect();
+
+        scheduled.extend(schedules.data.iter().map(|s| {
+            let trip_id = s.relationships.trip.data.id.clone();
+            let trip = trips.get(trip_id.as_str());
+            ScheduledTrip {
+                headsign: trip
+                    .and_then(|t| t.attributes.get("headsign")?.as_str())
+                    .map(|h| h.to_string()),
+                arrival: parse_time(s.attributes.arrival_time.clone()),
+                departure: parse_time(s.attributes.departure_time.clone()),
+                wheelchair_accessible: trip
+                    .and_then(|t| t.attributes.get("wheelchair_accessible")?.as_i64())
+                    .and_then(wheelchair_accessible),
+                trip_id,
+            }
+        }));
+        date += Duration::days(1);
+    }
+    Ok(scheduled)
+}
+
+/// The MBTA service date `t` falls on; service days run until 3 AM.
+pub fn service_date(t: DateTime<Local>) -> NaiveDate {
+    if t.hour() < SERVICE_DAY_END_HOUR {
+        t.date_naive() - Duration::days(1)
+    } else {
+        t.date_naive()
+    }
+}
+
+/// Formats `t` as an MBTA service-day time counted from `day_start`, so times
+/// after midnight are expressed as e.g. "24:30" rather than wrapping to "00:30",
+/// and times on days the clocks change match the feed's.
+fn service_time(t: DateTime<Local>, day_start: DateTime<Local>) -> String {
+    let minutes = t.signed_duration_since(day_start).num_minutes().max(0);
+    format!("{:02}:{:02}", minutes / 60, minutes % 60)
+}
+
+/// Batch-resolves the stop IDs missing from `stop_parent_map` (child stop ID ->
+/// parent station ID) with one `/stops` request. A stop without a parent maps to
+/// itself; on failure the IDs stay unresolved.
+pub async fn resolve_parents(client: &Client, stop_ids: &[String], stop_parent_map: &mut HashMap<String, String>) {
+    let unknown_ids: Vec<String> = stop_ids.iter()
+        .filter(|id| !stop_parent_map.contains_key(*id))
+        .cloned()
+        .collect::<std::collections::HashSet<_>>()
+        .into_iter()
+        .collect();
+    if unknown_ids.is_empty() {
+        return;
+    }
+    let Ok(text) = api_get(client, "/stops", &[("filter[id]", unknown_ids.join(","))]).await else {
+        return;
+    };
+    let parsed = match serde_json::from_str::<serde_json::Value>(&text) {
+        Ok(parsed) => parsed,
+        Err(_) => {
+            metrics::record_parse_failure("Stops");
+            return;
+        }
+    };
+    if let Some(data) = parsed.get("data").and_then(|d| d.as_array()) {
+        for item in data {
+            let id = item.get("id").and_then(|v| v.as_str()).unwrap_or("");
+            let parent_id = item.get("relationships")
+                .and_then(|r| r.get("parent_station"))
+                .and_then(|ps| ps.get("data"))
+                .and_then(|d| d.get("id"))
+                .and_then(|v| v.as_str())
+                .unwrap_or(id);
+            stop_parent_map.insert(id.to_string(), parent_id.to_string());
+        }
+    }
+}
+
+/// Fetches predictions (with vehicle data) for all `stops` of one route/direction in a
+/// single request, sharing the route's stop list for the stops-away count.
+async fn get_route_predictions(
+    client: &Client,
+    route_id: &str,
+    direction_id: i32,
+    stops: &[&StopConfig],
+) -> Result<RoutePredictions, Box<dyn Error>> {
+    let stop_ids: Vec<&str> = stops.iter().map(|s| s.stop_id).collect();
+    let pred_params = [
+        ("filter[stop]", stop_ids.join(",")),
+        ("filter[route]", route_id.to_string()),
+        ("filter[direction_id]", direction_id.to_string()),
+        ("sort", "arrival_time".to_string()),
+        ("include", "vehicle,stop".to_string()),
+    ];
+
+    let pred_resp: ApiResponse<Resource<PredictionAttributes, PredictionRelationships>> =
+        api_get_all(client, "/predictions", &pred_params, "Pred").await?;
+
+    // Extract vehicle current stop IDs and build child->parent stop map
+    let mut vehicle_stop_ids: HashMap<String, String> = HashMap::new(); // vehicle_id -> child stop ID
+    let mut stop_parent_map: HashMap<String, String> = HashMap::new(); // child stop ID -> parent station ID
+    for inc in &pred_resp.included {
+        if inc.resource_type == "vehicle" {
+            if let Some(stop_data) = inc.relationships.get("stop")
+                .and_then(|s| s.get("data"))
+                .and_then(|d| d.get("id"))
+                .and_then(|id| id.as_str()) {
+                vehicle_stop_ids.insert(inc.id.clone(), stop_data.to_string());
+            }
+        } else if inc.resource_type == "stop" {
+            let parent_id = inc.relationships.get("parent_station")
+                .and_then(|ps| ps.get("data"))
+                .and_then(|d| d.get("id"))
+                .and_then(|id| id.as_str())
+                .map(|s| s.to_string())
+                .unwrap_or_else(|| inc.id.clone());
+            stop_parent_map.insert(inc.id.clone(), parent_id);
+        }
+    }
+    // Collect all stop IDs we need to resolve (vehicle stops + prediction stops)
+    let pred_stop_ids: Vec<String> = pred_resp.data.iter()
+        .filter_map(|p| p.relationships.stop.as_ref().map(|s| s.data.id.clone()))
+        .collect();
+    let all_stop_ids: Vec<String> = vehicle_stop_ids.values().cloned()
+        .chain(pred_stop_ids)
+        .collect();
+    resolve_parents(client, &all_stop_ids, &mut stop_parent_map).await;
+    let to_parent = |id: &str| -> String {
+        stop_parent_map.get(id).cloned().unwrap_or_else(|| id.to_string())
+    };
+
+    // Fetch route stops list once for counting stops between vehicle and target
+    let route_stops_params = [
+        ("filter[route]", route_id.to_string()),
+        ("filter[direction_id]", direction_id.to_string()),
+    ];
+    let route_stop_ids: Vec<String> = match api_get(client, "/stops", &route_stops_params).await {
+        Ok(text) => serde_json::from_str::<RouteStopsResponse>(&text)
+            .map(|r| r.data.into_iter().map(|s| s.id).collect())
+            .unwrap_or_else(|_| {
+                metrics::record_parse_failure("Stops");
+                vec![]
+            }),
+        Err(_) => vec![],
+    };
+
+    // Fan predictions back out to the monitored stop they belong to, keyed by trip_id
+    let mut route_preds: RoutePredictions = HashMap::new();
+    for p in pred_resp.data {
+        let vehicle_stop = p.relationships.vehicle
+            .as_ref()
+            .and_then(|v| v.data.as_ref())
+            .and_then(|d| vehicle_stop_ids.get(&d.id).cloned());
+        let pred_stop = p.relationships.stop
+            .as_ref()
+            .map(|s| s.data.id.clone());
+
+        // A monitored stop may be a child stop or its parent station
+        let target = match &pred_stop {
+            Some(id) => {
+                let parent = to_parent(id);
+                stops.iter().find(|s| s.stop_id == id || s.stop_id == parent)
+            }
+            None if stops.len() == 1 => stops.first(),
+            None => None,
+        };
+        let Some(target) = target else { continue };
+
+        // Count actual stops between vehicle and target using route stops list
+        let stops_away = match (&vehicle_stop, &pred_stop) {
+            (Some(v_stop), Some(t_stop)) if !route_stop_ids.is_empty() => {
+                let v_parent = to_parent(v_stop);
+                let t_parent = to_parent(t_stop);
+                let v_idx = route_stop_ids.iter().position(|id| *id == v_parent);
+                let t_idx = route_stop_ids.iter().position(|id| *id == t_parent);
+                match (v_idx, t_idx) {
+                    (Some(vi), Some(ti)) => {
+                        let diff = (ti as i32 - vi as i32).unsigned_abs() as i32;
+                        if diff > 0 && diff <= 20 { Some(diff) } else { None }
+                    }
+                    _ => None,
+                }
+            }
+            _ => None,
+        };
+
+        route_preds
+            .entry(target.stop_id.to_string())
+            .or_default()
+            .insert(p.relationships.trip.data.id, StopPrediction {
+                arrival: parse_time(p.attributes.arrival_time),
+                departure: parse_time(p.attributes.departure_time),
+                stops_away,
+            });
+    }
+
+    Ok(route_preds)
+}
@@ -0,0 +1,113 @@
+//! Prometheus metrics for `b60 serve`: departure gauges computed per scrape and
+//! process-wide counters for MBTA API traffic.
+
+use chrono::{DateTime, Local};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+
+use crate::{RowData, StopConfig};
+
+/// (endpoint, status) -> count; status is "error" when no response arrived
+static API_REQUESTS: Mutex<BTreeMap<(String, String), u64>> = Mutex::new(BTreeMap::new());
+/// resource label -> count of response bodies that failed to parse
+static PARSE_FAILURES: Mutex<BTreeMap<String, u64>> = Mutex::new(BTreeMap::new());
+
+/// Counts one MBTA request, e.g. ("/predictions", Some(429)).
+pub fn record_request(endpoint: &str, status: Option<u16>) {
+    let status = status.map_or_else(|| "error".to_string(), |s| s.to_string());
+    let mut requests = API_REQUESTS.lock().unwrap();
+    *requests.entry((endpoint.to_string(), status)).or_default() += 1;
+}
+
+pub fn record_parse_failure(resource: &str) {
+    let mut failures = PARSE_FAILURES.lock().unwrap();
+    *failures.entry(resource.to_string()).or_default() += 1;
+}
+
+/// Escapes a label value (backslash, double quote, newline).
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn stop_labels(stop: &StopConfig) -> String {
+    format!(
+        "route=\"{}\",stop=\"{}\",stop_name=\"{}\",direction=\"{}\"",
+        escape(stop.route_id),
+        escape(stop.stop_id),
+        escape(stop.name),
+        stop.direction_id
+    )
+}
+
+fn push_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Renders the text exposition format. `rows` is None when departures could not
+/// be fetched, in which case only the counters are reported.
+pub fn render(stops: &[StopConfig], rows: Option<&[Vec<RowData>]>, now: DateTime<Local>) -> String {
+    let mut out = String::new();
+
+    if let Some(rows) = rows {
+        // The next trip at each stop that has not yet departed
+        let next: Vec<(&StopConfig, &RowData, DateTime<Local>)> = stops
+            .iter()
+            .zip(rows)
+            .filter_map(|(stop, rows)| {
+                rows.iter()
+                    .filter_map(|r| r.pred_dt.or(r.sched_dt).map(|t| (r, t)))
+                    .find(|(_, t)| *t >= now)
+                    .map(|(r, t)| (stop, r, t))
+            })
+            .collect();
+
+        push_header(&mut out, "b60_next_departure_seconds", "gauge", "Seconds until the next departure at the stop.");
+        for (stop, _, t) in &next {
+            let _ = writeln!(
+                out,
+                "b60_next_departure_seconds{{{}}} {}",
+                stop_labels(stop),
+                t.signed_duration_since(now).num_seconds()
+            );
+        }
+
+        push_header(&mut out, "b60_next_departure_live", "gauge", "1 if the next departure has a live prediction, 0 if scheduled only.");
+        for (stop, row, _) in &next {
+            let _ = writeln!(out, "b60_next_departure_live{{{}}} {}", stop_labels(stop), row.pred_dt.is_some() as u8);
+        }
+
+        push_header(&mut out, "b60_delay_seconds", "gauge", "Predicted minus scheduled time of the next departure.");
+        for (stop, row, _) in &next {
+            if let Some(delay) = row.delay_seconds() {
+                let _ = writeln!(out, "b60_delay_seconds{{{}}} {}", stop_labels(stop), delay);
+            }
+        }
+
+        push_header(&mut out, "b60_stops_away", "gauge", "Stops between the next departure's vehicle and the stop.");
+        for (stop, row, _) in &next {
+            if let Some(stops_away) = row.stops_away {
+                let _ = writeln!(out, "b60_stops_away{{{}}} {}", stop_labels(stop), stops_away);
+            }
+        }
+    }
+
+    push_header(&mut out, "b60_api_requests_total", "counter", "MBTA API requests by endpoint and HTTP status.");
+    for ((endpoint, status), count) in API_REQUESTS.lock().unwrap().iter() {
+        let _ = writeln!(
+            out,
+            "b60_api_requests_total{{endpoint=\"{}\",status=\"{}\"}} {}",
+            escape(endpoint),
+            status,
+            count
+        );
+    }
+
+    push_header(&mut out, "b60_api_parse_failures_total", "counter", "MBTA API responses that failed to parse.");
+    for (resource, count) in PARSE_FAILURES.lock().unwrap().iter() {
+        let _ = writeln!(out, "b60_api_parse_failures_total{{resource=\"{}\"}} {}", escape(resource), count);
+    }
+
+    out
+}
@@ -0,0 +1,306 @@
+//! `b60 notify`: watches live predictions and tells you when to leave for a stop.
+
+use chrono::{DateTime, Duration, Local};
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::io::Write;
+use tokio::process::Command;
+
+use crate::cli::{NotifyChannel, Options, DEFAULT_INTERVAL_SECS};
+use crate::headway::{self, HeadwayEvent};
+use crate::provider::Providers;
+use crate::replay;
+use crate::{fetch_stops, filter_rows, walk_minutes, RowData, StopConfig};
+
+/// A notified departure moving by at least this many minutes is announced again.
+const JUMP_MINUTES: i64 = 2;
+/// A departure stays catchable until this many seconds after its leave time,
+/// so a poll landing just past the threshold still fires.
+const LEAVE_GRACE_SECS: i64 = 60;
+
+#[derive(PartialEq)]
+enum AlertKind {
+    /// Time to leave for a departure
+    Leave,
+    /// A departure we already alerted for moved
+    Moved,
+    /// A departure we already alerted for moved earlier than we can make it
+    Missed,
+    /// The tracked departure lost its live prediction
+    Lost,
+    /// Another bus follows the departure within the bunching threshold
+    Bunched,
+    /// The bus after the departure comes much later than scheduled
+    Gap,
+}
+
+impl AlertKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            AlertKind::Leave => "leave",
+            AlertKind::Moved => "moved",
+            AlertKind::Missed => "missed",
+            AlertKind::Lost => "lost",
+            AlertKind::Bunched => "bunched",
+            AlertKind::Gap => "gap",
+        }
+    }
+}
+
+struct Alert {
+    kind: AlertKind,
+    title: String,
+    message: String,
+    stop_id: &'static str,
+    trip_id: String,
+    departure: DateTime<Local>,
+    leave_in_minutes: i64,
+}
+
+/// Per-stop memory across refreshes.
+#[derive(Default)]
+struct StopWatch {
+    /// trip_id -> predicted time when we last notified for it
+    notified: HashMap<String, DateTime<Local>>,
+    /// The trip we are currently waiting for and its last predicted time
+    tracked: Option<(String, DateTime<Local>)>,
+    /// Trips already alerted for bunching or a gap behind them
+    headway_alerted: HashSet<(String, &'static str)>,
+}
+
+impl StopWatch {
+    fn update(
+        &mut self,
+        stop: &StopConfig,
+        rows: &[RowData],
+        walk_minutes: i64,
+        before_minutes: i64,
+        events: &[HeadwayEvent],
+        now: DateTime<Local>,
+    ) -> Vec<Alert> {
+        let mut alerts = Vec::new();
+        let live: Vec<(&RowData, DateTime<Local>)> =
+            rows.iter().filter_map(|r| r.pred_dt.map(|p| (r, p))).collect();
+
+        // The trip we were told to leave for lost its prediction before departing
+        if let Some((trip_id, pred)) = self.tracked.take() {
+            if pred > now && self.notified.contains_key(&trip_id) && !live.iter().any(|(r, _)| r.trip_id == trip_id) {
+                let sched = rows.iter().find(|r| r.trip_id == trip_id).and_then(|r| r.sched_dt);
+                let fallback = match sched {
+                    Some(s) => format!(", scheduled {}", s.format("%H:%M")),
+                    None => String::new(),
+                };
+                alerts.push(Alert {
+                    kind: AlertKind::Lost,
+                    title: format!("⚠️ {} prediction lost", stop.route_id),
+                    message: format!(
+                        "{}: live prediction for the {} departure disappeared{}",
+                        stop.name,
+                        pred.format("%H:%M"),
+                        fallback
+                    ),
+                    stop_id: stop.stop_id,
+                    trip_id: trip_id.clone(),
+                    departure: sched.unwrap_or(pred),
+                    leave_in_minutes: (sched.unwrap_or(pred) - Duration::minutes(walk_minutes))
+                        .signed_duration_since(now)
+                        .num_minutes(),
+                });
+                self.notified.remove(&trip_id);
+            }
+        }
+
+        // Next departure we can still walk to
+        let walk = Duration::minutes(walk_minutes);
+        let next = live
+            .iter()
+            .find(|(_, pred)| (*pred - walk).signed_duration_since(now).num_seconds() >= -LEAVE_GRACE_SECS);
+
+        if let Some((row, pred)) = next {
+            let leave_secs = (*pred - walk).signed_duration_since(now).num_seconds();
+            let leave_in = (leave_secs / 60).max(0);
+            let when = if leave_in == 0 { "now".to_string() } else { format!("in {}m", leave_in) };
+            let departs = format!(
+                "{}: departs {} (in {}m), {}m walk",
+                stop.name,
+                pred.format("%H:%M:%S"),
+                pred.signed_duration_since(now).num_minutes(),
+                walk_minutes
+            );
+
+            match self.notified.get(&row.trip_id) {
+                None if leave_in <= before_minutes => {
+                    alerts.push(Alert {
+                        kind: AlertKind::Leave,
+                        title: format!("🚏 Leave {} for the {}", when, stop.route_id),
+                        message: departs,
+                        stop_id: stop.stop_id,
+                        trip_id: row.trip_id.clone(),
+                        departure: *pred,
+                        leave_in_minutes: leave_in,
+                    });
+                    self.notified.insert(row.trip_id.clone(), *pred);
+                }
+                Some(notified_pred) if (*pred - *notified_pred).num_minutes().abs() >= JUMP_MINUTES => {
+                    let moved = if *pred > *notified_pred { "later" } else { "earlier" };
+                    // Moved up past the point where we could still walk there in time
+                    let (kind, title) = if leave_secs < 0 {
+                        (AlertKind::Missed, format!("❌ {} moved {}: too late to make it", stop.route_id, moved))
+                    } else {
+                        (AlertKind::Moved, format!("⏱️ {} moved {}: leave {}", stop.route_id, moved, when))
+                    };
+                    alerts.push(Alert {
+                        kind,
+                        title,
+                        message: departs,
+                        stop_id: stop.stop_id,
+                        trip_id: row.trip_id.clone(),
+                        departure: *pred,
+                        leave_in_minutes: leave_in,
+                    });
+                    self.notified.insert(row.trip_id.clone(), *pred);
+                }
+                _ => {}
+            }
+
+            // Once we are heading for this departure, say what comes behind it
+            if self.notified.contains_key(&row.trip_id) {
+                for event in events {
+                    let (kind, title, message) = match event {
+                        HeadwayEvent::Bunched { trip_id, headway_secs, .. } if *trip_id == row.trip_id => (
+                            AlertKind::Bunched,
+                            format!("🚌🚌 {} bunched: next bus {}m behind", stop.route_id, headway_secs / 60),
+                            format!(
+                                "{}: another bus follows the {} departure {}m later",
+                                stop.name,
+                                pred.format("%H:%M"),
+                                headway_secs / 60
+                            ),
+                        ),
+                        HeadwayEvent::Gap { previous_trip_id, gap_secs, scheduled_secs, .. }
+                            if *previous_trip_id == row.trip_id =>
+                        {
+                            (
+                                AlertKind::Gap,
+                                format!("⏳ Don't miss the {}: next bus {}m later", stop.route_id, gap_secs / 60),
+                                format!(
+                                    "{}: after the {} departure the next bus is {}m later (scheduled every {}m)",
+                                    stop.name,
+                                    pred.format("%H:%M"),
+                                    gap_secs / 60,
+                                    scheduled_secs / 60
+                                ),
+                            )
+                        }
+                        _ => continue,
+                    };
+                    if self.headway_alerted.insert((row.trip_id.clone(), kind.as_str())) {
+                        alerts.push(Alert {
+                            kind,
+                            title,
+                            message,
+                            stop_id: stop.stop_id,
+                            trip_id: row.trip_id.clone(),
+                            departure: *pred,
+                            leave_in_minutes: leave_in,
+                        });
+                    }
+                }
+            }
+
+            self.tracked = Some((row.trip_id.clone(), *pred));
+        }
+
+        // Forget trips that are no longer in the feed
+        self.notified.retain(|trip_id, _| rows.iter().any(|r| r.trip_id == *trip_id));
+        self.headway_alerted.retain(|(trip_id, _)| rows.iter().any(|r| r.trip_id == *trip_id));
+
+        alerts
+    }
+}
+
+/// Prints the alert and hands it to each `--notify-via` channel. Commands are
+/// spawned without waiting so a slow one doesn't hold up polling.
+fn fire(alert: &Alert, options: &Options) {
+    println!("[{}] {} — {}", Local::now().format("%H:%M:%S"), alert.title, alert.message);
+
+    for channel in &options.notify_via {
+        match channel {
+            NotifyChannel::Desktop => {
+                let result = Command::new("notify-send")
+                    .args(["--app-name=b60", "--urgency=critical", &alert.title, &alert.message])
+                    .spawn();
+                if let Err(e) = result {
+                    eprintln!("⚠️  notify-send failed: {}", e);
+                }
+            }
+            NotifyChannel::Bell => {
+                print!("\x07");
+                let _ = std::io::stdout().flush();
+            }
+            NotifyChannel::Command => {
+                let Some(cmd) = &options.notify_exec else { continue };
+                let result = Command::new("sh")
+                    .args(["-c", cmd])
+                    .env("B60_KIND", alert.kind.as_str())
+                    .env("B60_TITLE", &alert.title)
+                    .env("B60_MESSAGE", &alert.message)
+                    .env("B60_STOP_ID", alert.stop_id)
+                    .env("B60_TRIP_ID", &alert.trip_id)
+                    .env("B60_DEPARTURE", alert.departure.to_rfc3339())
+                    .env("B60_LEAVE_IN", alert.leave_in_minutes.to_string())
+                    .spawn();
+                if let Err(e) = result {
+                    eprintln!("⚠️  Notification command failed: {}", e);
+                }
+            }
+        }
+    }
+}
+
+/// Polls predictions for `stops` until interrupted (or the first leave alert with `--once`).
+pub async fn run(providers: &Providers, stops: &[StopConfig], options: &Options) -> Result<(), Box<dyn Error>> {
+    let interval = options
+        .watch
+        .unwrap_or(std::time::Duration::from_secs(DEFAULT_INTERVAL_SECS));
+    let mut watches: Vec<StopWatch> = stops.iter().map(|_| StopWatch::default()).collect();
+
+    for stop in stops {
+        eprintln!(
+            "Watching {} for departures (walk {}m, alert {}m before leaving)",
+            stop.name,
+            walk_minutes(options, stop),
+            options.notify_before_minutes
+        );
+    }
+
+    loop {
+        let now = replay::now();
+        let results = fetch_stops(providers, stops, &options.window, now).await;
+        if results.iter().any(|r| r.as_ref().is_err_and(|e| e.to_string() == "Rate limited")) {
+            eprintln!("⚠️  MBTA API rate limit exceeded, retrying");
+        }
+
+        for ((stop, result), watch) in stops.iter().zip(results).zip(&mut watches) {
+            // A failed fetch must not look like the tracked trip losing its prediction
+            let rows = match result {
+                Ok(rows) => filter_rows(rows, &options.window.for_stop(stop), now),
+                Err(e) if e.to_string() == "Rate limited" => continue,
+                Err(e) => {
+                    eprintln!("⚠️  Error fetching {} data: {}", stop.name, e);
+                    continue;
+                }
+            };
+            let events = headway::detect(&rows, options.bunch_minutes);
+            let walk = walk_minutes(options, stop);
+            for alert in watch.update(stop, &rows, walk, options.notify_before_minutes, &events, now) {
+                fire(&alert, options);
+                if options.once && alert.kind == AlertKind::Leave {
+                    return Ok(());
+                }
+            }
+        }
+
+        tokio::time::sleep(interval).await;
+    }
+}
@@ -0,0 +1,498 @@
+//! `b60 plan`: chains the legs of a configured journey into itineraries, using
+//! live predictions where available and schedules otherwise.
+
+use chrono::{DateTime, Duration, Local};
+use std::error::Error;
+use std::io::IsTerminal;
+
+use crate::cli::Options;
+use crate::provider::Providers;
+use crate::replay;
+use crate::{
+    fetch_filtered_rows, format_delay, paint, route_color, JourneyConfig, LegConfig, RowData,
+    StopConfig, WindowOverrides, STYLE_BOLD, STYLE_DIM, STYLE_IMMINENT,
+};
+
+/// The trip taken on one leg.
+struct LegTrip {
+    depart: DateTime<Local>,
+    depart_live: bool,
+    arrive: DateTime<Local>,
+}
+
+struct Itinerary {
+    /// When to leave for the first boarding stop
+    leave_at: DateTime<Local>,
+    legs: Vec<LegTrip>,
+    /// Seconds to spare at each transfer after walking, one per leg after the first
+    slack_secs: Vec<i64>,
+}
+
+impl Itinerary {
+    fn arrive(&self) -> DateTime<Local> {
+        self.legs.last().map(|l| l.arrive).unwrap_or(self.leave_at)
+    }
+}
+
+fn board_stop(leg: &LegConfig) -> StopConfig {
+    StopConfig {
+        name: leg.board_name,
+        route_id: leg.route_id,
+        stop_id: leg.board_stop_id,
+        direction_id: leg.direction_id,
+        is_origin: leg.board_is_origin,
+        window: WindowOverrides::default(),
+        walk_minutes: Some(leg.walk_minutes),
+        provider: leg.provider,
+    }
+}
+
+fn alight_stop(leg: &LegConfig) -> StopConfig {
+    StopConfig {
+        name: leg.alight_name,
+        route_id: leg.route_id,
+        stop_id: leg.alight_stop_id,
+        direction_id: leg.direction_id,
+        is_origin: false,
+        window: WindowOverrides::default(),
+        walk_minutes: None,
+        provider: leg.provider,
+    }
+}
+
+/// Predicted time when available, otherwise scheduled; with whether it is live.
+fn best_time(row: &RowData) -> Option<(DateTime<Local>, bool)> {
+    row.pred_dt.map(|p| (p, true)).or(row.sched_dt.map(|s| (s, false)))
+}
+
+/// Rides `row`'s trip to the alighting stop, if the trip is found there.
+fn ride(row: &RowData, alight_rows: &[RowData]) -> Option<LegTrip> {
+    let (depart, depart_live) = best_time(row)?;
+    let (arrive, _) = alight_rows
+        .iter()
+        .find(|r| r.trip_id == row.trip_id)
+        .and_then(best_time)?;
+    (arrive >= depart).then_some(LegTrip {
+        depart,
+        depart_live,
+        arrive,
+    })
+}
+
+/// Builds one itinerary per catchable first-leg departure, taking the earliest
+/// connection at each transfer, and drops itineraries that arrive no earlier
+/// than one leaving later.
+fn itineraries(
+    journey: &JourneyConfig,
+    board_rows: &[Vec<RowData>],
+    alight_rows: &[Vec<RowData>],
+    now: DateTime<Local>,
+) -> Vec<Itinerary> {
+    let Some(first_leg) = journey.legs.first() else { return Vec::new() };
+    let mut result: Vec<Itinerary> = Vec::new();
+
+    for first in &board_rows[0] {
+        let Some(first_trip) = ride(first, &alight_rows[0]) else { continue };
+        let leave_at = first_trip.depart - Duration::minutes(first_leg.walk_minutes);
+        if leave_at < now {
+            continue;
+        }
+
+        let mut legs = vec![first_trip];
+        let mut slack_secs = Vec::new();
+        for (i, leg) in journey.legs.iter().enumerate().skip(1) {
+            let ready = legs[i - 1].arrive + Duration::minutes(leg.walk_minutes);
+            let connection = board_rows[i]
+                .iter()
+                .filter_map(|r| ride(r, &alight_rows[i]))
+                .filter(|t| t.depart >= ready)
+                .min_by_key(|t| t.depart);
+            let Some(trip) = connection else { break };
+            slack_secs.push(trip.depart.signed_duration_since(ready).num_seconds());
+            legs.push(trip);
+        }
+
+        if legs.len() == journey.legs.len() {
+            result.push(Itinerary { leave_at, legs, slack_secs });
+        }
+    }
+
+    // An itinerary is dominated when another one leaves later and arrives no later
+    let dominated = |it: &Itinerary| {
+        result
+            .iter()
+            .any(|other| other.leave_at > it.leave_at && other.arrive() <= it.arrive())
+    };
+    let keep: Vec<bool> = result.iter().map(|it| !dominated(it)).collect();
+    let mut keep = keep.into_iter();
+    result.retain(|_| keep.next().unwrap_or(false));
+    result.sort_by_key(|it| it.arrive());
+    result
+}
+
+fn print_itinerary(
+    index: usize,
+    journey: &JourneyConfig,
+    it: &Itinerary,
+    min_slack_minutes: i64,
+    color: bool,
+    now: DateTime<Local>,
+) {
+    let arrive = it.arrive();
+    let last = journey.legs.last().map(|l| l.alight_name).unwrap_or("");
+    println!(
+        "{}. {}",
+        index + 1,
+        paint(
+            &format!(
+                "Leave {} (in {}m) · arrive {} {} ({}m total)",
+                it.leave_at.format("%H:%M"),
+                it.leave_at.signed_duration_since(now).num_minutes(),
+                last,
+                arrive.format("%H:%M"),
+                arrive.signed_duration_since(it.leave_at).num_minutes()
+            ),
+            STYLE_BOLD,
+            color
+        )
+    );
+
+    for (i, (leg, trip)) in journey.legs.iter().zip(&it.legs).enumerate() {
+        let walk = if i == 0 {
+            format!("🚶 {}m walk to {}", leg.walk_minutes, leg.board_name)
+        } else {
+            format!(
+                "🚶 {}m walk, {}m slack",
+                leg.walk_minutes,
+                it.slack_secs[i - 1] / 60
+            )
+        };
+        if i > 0 && it.slack_secs[i - 1] < min_slack_minutes * 60 {
+            println!("   {} {}", walk, paint("⚠️ connection at risk", STYLE_IMMINENT, color));
+        } else {
+            println!("   {}", paint(&walk, STYLE_DIM, color));
+        }
+
+        println!(
+            "   {} {} {} {} → {} {}",
+            if trip.depart_live { "🟢" } else { "📅" },
+            paint(leg.route_id, route_color(leg.route_id), color),
+            leg.board_name,
+            trip.depart.format("%H:%M"),
+            leg.alight_name,
+            trip.arrive.format("%H:%M"),
+        );
+    }
+}
+
+/// The journey named by `options.journey`, or every journey.
+fn select_journeys<'a>(journeys: &'a [JourneyConfig], options: &Options) -> Result<Vec<&'a JourneyConfig>, Box<dyn Error>> {
+    match &options.journey {
+        Some(name) => match journeys.iter().find(|j| j.name == name.as_str()) {
+            Some(journey) => Ok(vec![journey]),
+            None => {
+                let names: Vec<&str> = journeys.iter().map(|j| j.name).collect();
+                Err(format!("no journey named {} (configured: {})", name, names.join(", ")).into())
+            }
+        },
+        None => Ok(journeys.iter().collect()),
+    }
+}
+
+/// Rows at each leg's boarding stop and at its alighting stop (arrival times).
+async fn fetch_journey_rows(
+    providers: &Providers,
+    journey: &JourneyConfig,
+    options: &Options,
+    err_color: bool,
+    now: DateTime<Local>,
+) -> Result<(Vec<Vec<RowData>>, Vec<Vec<RowData>>), Box<dyn Error>> {
+    let stops: Vec<StopConfig> = journey
+        .legs
+        .iter()
+        .flat_map(|leg| [board_stop(leg), alight_stop(leg)])
+        .collect();
+
+    let rows = fetch_filtered_rows(providers, &stops, &options.window, err_color, now)
+        .await
+        .ok_or("MBTA API rate limit exceeded. Please wait a moment and try again.")?;
+    let board_rows: Vec<Vec<RowData>> = rows.iter().step_by(2).cloned().collect();
+    let alight_rows: Vec<Vec<RowData>> = rows.iter().skip(1).step_by(2).cloned().collect();
+    Ok((board_rows, alight_rows))
+}
+
+/// Lists the next feasible itineraries for `options.journey` (or every journey).
+pub async fn run(providers: &Providers, journeys: &[JourneyConfig], options: &Options) -> Result<(), Box<dyn Error>> {
+    let color = options.color.enabled(std::io::stdout().is_terminal());
+    let err_color = options.color.enabled(std::io::stderr().is_terminal());
+
+    for journey in select_journeys(journeys, options)? {
+        let now = replay::now();
+        let (board_rows, alight_rows) = fetch_journey_rows(providers, journey, options, err_color, now).await?;
+
+        println!("{}", paint(&format!("Journey {}:", journey.name), STYLE_BOLD, color));
+        let its = itineraries(journey, &board_rows, &alight_rows, now);
+        if its.is_empty() {
+            println!("{}", paint("No feasible itineraries", STYLE_DIM, color));
+        }
+        for (i, it) in its.iter().take(options.window.trips).enumerate() {
+            print_itinerary(i, journey, it, options.min_slack_minutes, color, now);
+        }
+        println!();
+    }
+
+    Ok(())
+}
+
+/// How an incoming trip connects to the outgoing route at a transfer.
+struct Connection<'a> {
+    incoming: &'a RowData,
+    arrive: DateTime<Local>,
+    /// First outgoing departure reachable after walking, by live times
+    outgoing: Option<&'a RowData>,
+    /// Seconds to spare before `outgoing` departs
+    slack_secs: Option<i64>,
+    /// The departure the timetable connects to, when the live one differs
+    missed: Option<&'a RowData>,
+}
+
+/// Matches each incoming arrival at the transfer stop with the outgoing
+/// route's next departures, comparing the live connection with the timetabled one.
+fn connections<'a>(
+    incoming_rows: &'a [RowData],
+    outgoing_rows: &'a [RowData],
+    walk_minutes: i64,
+    now: DateTime<Local>,
+) -> Vec<Connection<'a>> {
+    let walk = Duration::minutes(walk_minutes);
+    incoming_rows
+        .iter()
+        .filter_map(|incoming| {
+            let (arrive, _) = best_time(incoming)?;
+            if arrive < now {
+                return None;
+            }
+
+            let ready = arrive + walk;
+            let outgoing = outgoing_rows
+                .iter()
+                .filter(|r| best_time(r).is_some_and(|(t, _)| t >= ready))
+                .min_by_key(|r| best_time(r).map(|(t, _)| t));
+            let slack_secs = outgoing
+                .and_then(best_time)
+                .map(|(t, _)| t.signed_duration_since(ready).num_seconds());
+
+            // The connection the timetable promises, by scheduled times only
+            let planned = incoming.sched_dt.and_then(|sched_arrive| {
+                outgoing_rows
+                    .iter()
+                    .filter(|r| r.sched_dt.is_some_and(|t| t >= sched_arrive + walk))
+                    .min_by_key(|r| r.sched_dt)
+            });
+            let missed = match (planned, outgoing) {
+                (Some(p), Some(o)) if p.trip_id != o.trip_id => Some(p),
+                (Some(p), None) => Some(p),
+                _ => None,
+            };
+
+            Some(Connection { incoming, arrive, outgoing, slack_secs, missed })
+        })
+        .collect()
+}
+
+fn print_connection(conn: &Connection, incoming: &LegConfig, outgoing: &LegConfig, min_slack_minutes: i64, color: bool) {
+    let marker = |row: &RowData| if row.pred_dt.is_some() { "🟢" } else { "📅" };
+    let mut line = format!(
+        "  {} {} arrives {}",
+        marker(conn.incoming),
+        paint(incoming.route_id, route_color(incoming.route_id), color),
+        conn.arrive.format("%H:%M")
+    );
+    if let Some(delay) = conn.incoming.delay_seconds() {
+        line.push_str(&format!(" ({})", format_delay(delay)));
+    }
+
+    match (conn.outgoing, conn.outgoing.and_then(best_time)) {
+        (Some(out), Some((depart, _))) => {
+            line.push_str(&format!(
+                " → {} {} {} · {}m slack",
+                marker(out),
+                paint(outgoing.route_id, route_color(outgoing.route_id), color),
+                depart.format("%H:%M"),
+                conn.slack_secs.unwrap_or(0) / 60
+            ));
+        }
+        _ => line.push_str(" → no connection"),
+    }
+
+    if let Some(planned) = conn.missed.and_then(best_time) {
+        let warning = format!("⚠️ misses the {}", planned.0.format("%H:%M"));
+        line.push_str(&format!(" {}", paint(&warning, STYLE_IMMINENT, color)));
+    } else if conn.slack_secs.is_some_and(|s| s < min_slack_minutes * 60) {
+        line.push_str(&format!(" {}", paint("⚠️ connection at risk", STYLE_IMMINENT, color)));
+    }
+    println!("{}", line);
+}
+
+/// Checks every transfer of `options.journey` (or every journey): for the next
+/// incoming arrivals, which outgoing departure they make and with how much slack.
+pub async fn run_transfers(providers: &Providers, journeys: &[JourneyConfig], options: &Options) -> Result<(), Box<dyn Error>> {
+    let color = options.color.enabled(std::io::stdout().is_terminal());
+    let err_color = options.color.enabled(std::io::stderr().is_terminal());
+
+    for journey in select_journeys(journeys, options)? {
+        let now = replay::now();
+        let (board_rows, alight_rows) = fetch_journey_rows(providers, journey, options, err_color, now).await?;
+
+        for (i, pair) in journey.legs.windows(2).enumerate() {
+            let (incoming, outgoing) = (&pair[0], &pair[1]);
+            println!(
+                "{}",
+                paint(
+                    &format!(
+                        "Transfer {} → {} ({} → {}, {}m walk):",
+                        incoming.alight_name, outgoing.board_name, incoming.route_id, outgoing.route_id, outgoing.walk_minutes
+                    ),
+                    STYLE_BOLD,
+                    color
+                )
+            );
+
+            let conns = connections(&alight_rows[i], &board_rows[i + 1], outgoing.walk_minutes, now);
+            if conns.is_empty() {
+                println!("{}", paint("  No incoming trips", STYLE_DIM, color));
+            }
+            for conn in conns.iter().take(options.window.trips) {
+                print_connection(conn, incoming, outgoing, options.min_slack_minutes, color);
+            }
+            println!();
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn now() -> DateTime<Local> {
+        Local.with_ymd_and_hms(2026, 10, 18, 12, 0, 0).unwrap()
+    }
+
+    /// A trip at `minutes` after `now()` by schedule, and by prediction when given.
+    fn row(trip_id: &str, sched_minutes: i64, pred_minutes: Option<i64>) -> RowData {
+        RowData {
+            trip_id: trip_id.to_string(),
+            headsign: None,
+            sched_dt: Some(now() + Duration::minutes(sched_minutes)),
+            pred_dt: pred_minutes.map(|m| now() + Duration::minutes(m)),
+            stops_away: None,
+            wheelchair_accessible: None,
+        }
+    }
+
+    fn leg(route_id: &'static str, walk_minutes: i64) -> LegConfig {
+        LegConfig {
+            route_id,
+            direction_id: 0,
+            board_name: "Board",
+            board_stop_id: "1",
+            board_is_origin: false,
+            alight_name: "Alight",
+            alight_stop_id: "2",
+            walk_minutes,
+            provider: "mbta",
+        }
+    }
+
+    fn journey() -> JourneyConfig {
+        JourneyConfig { name: "test", legs: vec![leg("60", 5), leg("Green-D", 2)] }
+    }
+
+    #[test]
+    fn itineraries_skip_trips_you_cannot_make() {
+        // A leaves before the 5m walk gets you there; X leaves before you reach it from A or B
+        let board = vec![
+            vec![row("A", 3, None), row("B", 10, None)],
+            vec![row("X", 21, None), row("Y", 25, Some(26))],
+        ];
+        let alight = vec![
+            vec![row("A", 13, None), row("B", 20, None)],
+            vec![row("X", 31, None), row("Y", 35, Some(36))],
+        ];
+
+        let result = itineraries(&journey(), &board, &alight, now());
+        assert_eq!(result.len(), 1);
+        let it = &result[0];
+        assert_eq!(it.leave_at, now() + Duration::minutes(5));
+        assert_eq!(it.legs[1].depart, now() + Duration::minutes(26));
+        assert!(it.legs[1].depart_live);
+        assert_eq!(it.slack_secs, vec![4 * 60]);
+        assert_eq!(it.arrive(), now() + Duration::minutes(36));
+
+        // No connection for the second leg leaves no itinerary at all
+        let board = vec![vec![row("B", 10, None)], vec![row("X", 21, None)]];
+        let alight = vec![vec![row("B", 20, None)], vec![row("X", 31, None)]];
+        assert!(itineraries(&journey(), &board, &alight, now()).is_empty());
+    }
+
+    #[test]
+    fn itineraries_are_ordered_by_arrival_leaving_as_late_as_possible() {
+        // B and D both make Y; D leaves later, so B is dropped
+        let board = vec![
+            vec![row("B", 10, None), row("C", 20, None), row("D", 12, None)],
+            vec![row("Y", 25, None), row("Z", 35, None)],
+        ];
+        let alight = vec![
+            vec![row("B", 20, None), row("C", 30, None), row("D", 23, None)],
+            vec![row("Y", 35, None), row("Z", 45, None)],
+        ];
+
+        let result = itineraries(&journey(), &board, &alight, now());
+        let summary: Vec<(i64, i64)> = result
+            .iter()
+            .map(|it| {
+                (
+                    it.leave_at.signed_duration_since(now()).num_minutes(),
+                    it.arrive().signed_duration_since(now()).num_minutes(),
+                )
+            })
+            .collect();
+        assert_eq!(summary, vec![(7, 35), (15, 45)]);
+        assert_eq!(result[0].slack_secs, vec![0]);
+    }
+
+    #[test]
+    fn connections_leave_time_to_walk_between_stops() {
+        // Arriving at 10 with a 3m walk, the 12 is too soon and the 15 is caught
+        let incoming = vec![row("I1", 10, None)];
+        let outgoing = vec![row("O1", 12, None), row("O2", 15, None), row("O3", 20, None)];
+
+        let result = connections(&incoming, &outgoing, 3, now());
+        assert_eq!(result.len(), 1);
+        assert_eq!(result[0].outgoing.map(|r| r.trip_id.as_str()), Some("O2"));
+        assert_eq!(result[0].slack_secs, Some(2 * 60));
+        assert!(result[0].missed.is_none());
+    }
+
+    #[test]
+    fn connections_that_cannot_be_made_are_dropped_or_flagged() {
+        // I0 already arrived; I1 runs 5m late and misses the timetabled O1;
+        // nothing leaves after I2
+        let incoming = vec![row("I0", -2, None), row("I1", 5, Some(10)), row("I2", 20, None)];
+        let outgoing = vec![row("O1", 9, None), row("O2", 12, Some(12)), row("O3", 15, None)];
+
+        let result = connections(&incoming, &outgoing, 3, now());
+        let ids: Vec<&str> = result.iter().map(|c| c.incoming.trip_id.as_str()).collect();
+        assert_eq!(ids, vec!["I1", "I2"]);
+
+        assert_eq!(result[0].outgoing.map(|r| r.trip_id.as_str()), Some("O3"));
+        assert_eq!(result[0].missed.map(|r| r.trip_id.as_str()), Some("O1"));
+
+        assert!(result[1].outgoing.is_none());
+        assert!(result[1].slack_secs.is_none());
+        assert!(result[1].missed.is_none());
+    }
+}
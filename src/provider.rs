@@ -0,0 +1,236 @@
+//! Transit data providers. Each stop names the provider its schedules and
+//! predictions come from, so stops of several agencies can be monitored side by
+//! side; merging, filtering and rendering are shared.
+
+use chrono::{DateTime, Local};
+use futures::future::LocalBoxFuture;
+use reqwest::Client;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::error::Error;
+
+use crate::cli::{Options, ProviderSpec};
+use crate::gtfs::GtfsStore;
+use crate::mbta::MbtaV3;
+use crate::{
+    gtfs_rt, parse_time, RoutePredictions, ScheduledTrip, StopConfig, StopOutages, StopPrediction, WindowSettings,
+};
+
+/// The provider stops use unless they name another.
+pub const DEFAULT_PROVIDER: &str = "mbta";
+
+/// Stops of one route and direction, whose predictions are fetched together.
+pub struct RouteGroup<'a> {
+    pub route_id: &'a str,
+    pub direction_id: i32,
+    pub stops: Vec<&'a StopConfig>,
+}
+
+pub trait Provider {
+    /// Scheduled trips at `stop` from `window.lookback_minutes` before `now`
+    /// until the horizon, or `SCHEDULE_WINDOW_MINUTES` when there is none.
+    fn schedules<'a>(
+        &'a self,
+        stop: &'a StopConfig,
+        window: WindowSettings,
+        now: DateTime<Local>,
+    ) -> LocalBoxFuture<'a, Result<Vec<ScheduledTrip>, Box<dyn Error>>>;
+
+    /// Live predictions for each group, in the same order as `groups`.
+    fn predictions<'a>(
+        &'a self,
+        groups: &'a [RouteGroup<'a>],
+    ) -> LocalBoxFuture<'a, Vec<Result<RoutePredictions, Box<dyn Error>>>>;
+
+    /// Whether predictions are fetched from the network and so describe the
+    /// real time. Under `--at` these are dropped, while saved ones answer for
+    /// the simulated time.
+    fn live_predictions(&self) -> bool {
+        true
+    }
+
+    /// Elevators and escalators closed at `now` at the stations of `stops`.
+    /// Providers without facility data report none.
+    fn outages<'a>(
+        &'a self,
+        _stops: &'a [&'a StopConfig],
+        _now: DateTime<Local>,
+    ) -> LocalBoxFuture<'a, Result<StopOutages, Box<dyn Error>>> {
+        Box::pin(async { Ok(HashMap::new()) })
+    }
+}
+
+/// Any agency's GTFS feed, live when GTFS-realtime feeds are given and
+/// schedule-only otherwise.
+pub struct GtfsProvider {
+    client: Client,
+    store: GtfsStore,
+    realtime: Vec<String>,
+}
+
+impl Provider for GtfsProvider {
+    fn live_predictions(&self) -> bool {
+        self.realtime.iter().any(|s| gtfs_rt::is_url(s))
+    }
+
+    fn schedules<'a>(
+        &'a self,
+        stop: &'a StopConfig,
+        window: WindowSettings,
+        now: DateTime<Local>,
+    ) -> LocalBoxFuture<'a, Result<Vec<ScheduledTrip>, Box<dyn Error>>> {
+        Box::pin(async move { self.store.schedules(stop, window, now) })
+    }
+
+    fn predictions<'a>(
+        &'a self,
+        groups: &'a [RouteGroup<'a>],
+    ) -> LocalBoxFuture<'a, Vec<Result<RoutePredictions, Box<dyn Error>>>> {
+        Box::pin(async move {
+            if self.realtime.is_empty() {
+                return groups.iter().map(|_| Ok(HashMap::new())).collect();
+            }
+            gtfs_rt::group_predictions(&self.client, &self.realtime, &self.store, groups).await
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct FixtureSnapshot {
+    stops: Vec<FixtureStop>,
+}
+
+#[derive(Deserialize)]
+struct FixtureStop {
+    route_id: String,
+    stop_id: String,
+    direction_id: i32,
+    trips: Vec<FixtureTrip>,
+}
+
+#[derive(Deserialize)]
+struct FixtureTrip {
+    trip_id: String,
+    headsign: Option<String>,
+    scheduled_time: Option<String>,
+    predicted_time: Option<String>,
+    stops_away: Option<i32>,
+}
+
+/// Departures from a snapshot written by `b60 --format json`. The times are
+/// served as recorded, whatever the current time.
+pub struct FixtureProvider {
+    stops: Vec<FixtureStop>,
+}
+
+impl FixtureProvider {
+    pub fn open(path: &str) -> Result<FixtureProvider, Box<dyn Error>> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        let snapshot: FixtureSnapshot = serde_json::from_str(&text).map_err(|e| format!("{}: {}", path, e))?;
+        Ok(FixtureProvider { stops: snapshot.stops })
+    }
+
+    fn find(&self, stop: &StopConfig) -> Option<&FixtureStop> {
+        self.stops.iter().find(|s| {
+            s.stop_id == stop.stop_id && s.route_id == stop.route_id && s.direction_id == stop.direction_id
+        })
+    }
+}
+
+impl Provider for FixtureProvider {
+    fn live_predictions(&self) -> bool {
+        false
+    }
+
+    fn schedules<'a>(
+        &'a self,
+        stop: &'a StopConfig,
+        _window: WindowSettings,
+        _now: DateTime<Local>,
+    ) -> LocalBoxFuture<'a, Result<Vec<ScheduledTrip>, Box<dyn Error>>> {
+        let trips = self.find(stop).map_or_else(Vec::new, |s| {
+            s.trips
+                .iter()
+                .map(|t| {
+                    let scheduled = parse_time(t.scheduled_time.clone());
+                    ScheduledTrip {
+                        trip_id: t.trip_id.clone(),
+                        headsign: t.headsign.clone(),
+                        arrival: scheduled,
+                        departure: scheduled,
+                        wheelchair_accessible: None,
+                    }
+                })
+                .collect()
+        });
+        Box::pin(async move { Ok(trips) })
+    }
+
+    fn predictions<'a>(
+        &'a self,
+        groups: &'a [RouteGroup<'a>],
+    ) -> LocalBoxFuture<'a, Vec<Result<RoutePredictions, Box<dyn Error>>>> {
+        let results = groups
+            .iter()
+            .map(|g| {
+                let mut route_preds: RoutePredictions = HashMap::new();
+                for stop in &g.stops {
+                    let Some(fixture) = self.find(stop) else { continue };
+                    for t in &fixture.trips {
+                        let Some(predicted) = parse_time(t.predicted_time.clone()) else { continue };
+                        route_preds.entry(stop.stop_id.to_string()).or_default().insert(
+                            t.trip_id.clone(),
+                            StopPrediction { arrival: Some(predicted), departure: Some(predicted), stops_away: t.stops_away },
+                        );
+                    }
+                }
+                Ok(route_preds)
+            })
+            .collect();
+        Box::pin(async move { results })
+    }
+}
+
+/// The providers stops can name, and the HTTP client they share.
+pub struct Providers {
+    pub client: Client,
+    by_name: HashMap<String, Box<dyn Provider>>,
+}
+
+impl Providers {
+    /// Opens `mbta` (with `--gtfs`/`--gtfs-rt` applied) and every `--provider`;
+    /// a `--provider mbta=...` replaces the default.
+    pub fn open(options: &Options) -> Result<Providers, Box<dyn Error>> {
+        let client = Client::new();
+        let mut by_name: HashMap<String, Box<dyn Provider>> = HashMap::new();
+
+        let open_mbta = || -> Result<Box<dyn Provider>, Box<dyn Error>> {
+            let gtfs = options.gtfs.as_deref().map(GtfsStore::open).transpose()?;
+            Ok(Box::new(MbtaV3::new(client.clone(), gtfs, options.gtfs_rt.clone())))
+        };
+        if !options.providers.iter().any(|(name, _)| name == DEFAULT_PROVIDER) {
+            by_name.insert(DEFAULT_PROVIDER.to_string(), open_mbta()?);
+        }
+        for (name, spec) in &options.providers {
+            let provider: Box<dyn Provider> = match spec {
+                ProviderSpec::Mbta => open_mbta()?,
+                ProviderSpec::Gtfs { zip, realtime } => Box::new(GtfsProvider {
+                    client: client.clone(),
+                    store: GtfsStore::open(zip)?,
+                    realtime: realtime.clone(),
+                }),
+                ProviderSpec::Fixtures(path) => Box::new(FixtureProvider::open(path)?),
+            };
+            by_name.insert(name.clone(), provider);
+        }
+
+        Ok(Providers { client, by_name })
+    }
+
+    pub fn get(&self, name: &str) -> Result<&dyn Provider, String> {
+        self.by_name
+            .get(name)
+            .map(|p| p.as_ref())
+            .ok_or_else(|| format!("unknown provider: {} (configure it with --provider {}=...)", name, name))
+    }
+}
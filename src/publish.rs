@@ -0,0 +1,400 @@
+//! `b60 publish`: pushes per-stop departure snapshots to HTTP webhooks and an
+//! MQTT broker on every refresh, with Home Assistant discovery.
+
+use chrono::{DateTime, Local};
+use reqwest::Client;
+use rumqttc::{AsyncClient, ClientError, Event, Incoming, MqttOptions, Outgoing, QoS};
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+
+use crate::cli::{Options, DEFAULT_INTERVAL_SECS};
+use crate::provider::Providers;
+use crate::replay;
+use crate::{fetch_filtered_rows, headway, stop_json, walk_minutes, RowData, StopConfig};
+
+/// A snapshot published for the stop at an index of `run`'s stops.
+type StopSnapshot = (usize, serde_json::Value);
+
+const DEFAULT_MQTT_PORT: u16 = 1883;
+const HA_DISCOVERY_PREFIX: &str = "homeassistant";
+
+/// Expands `{route}`, `{stop}` and `{direction}` in a topic template.
+fn topic_for(template: &str, stop: &StopConfig) -> String {
+    template
+        .replace("{route}", stop.route_id)
+        .replace("{stop}", stop.stop_id)
+        .replace("{direction}", &stop.direction_id.to_string())
+}
+
+/// Identifier safe for Home Assistant object ids and unique ids.
+fn object_id(stop: &StopConfig) -> String {
+    let raw = format!("b60_{}_{}_{}", stop.route_id, stop.stop_id, stop.direction_id);
+    raw.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '_' { c.to_ascii_lowercase() } else { '_' })
+        .collect()
+}
+
+/// The stop's trips as in `--format json`, plus walking time, the next departure
+/// and any bunching or gaps.
+/// Excludes the generation time so unchanged snapshots compare equal.
+fn snapshot(stop: &StopConfig, rows: &[RowData], options: &Options, now: DateTime<Local>) -> serde_json::Value {
+    let mut doc = stop_json(stop, rows, &options.window.for_stop(stop));
+    // Rows are kept for a while after they leave, so skip the ones already gone
+    let next = rows.iter().filter_map(|r| r.pred_dt.or(r.sched_dt)).find(|t| *t >= now);
+    doc["walk_minutes"] = walk_minutes(options, stop).into();
+    doc["next_departure"] = next.map(|t| t.to_rfc3339()).into();
+    doc["headway_events"] = headway::detect(rows, options.bunch_minutes)
+        .iter()
+        .map(|e| e.to_json())
+        .collect::<Vec<_>>()
+        .into();
+    doc
+}
+
+/// Home Assistant MQTT discovery config for a timestamp sensor per stop.
+/// The whole snapshot is exposed as the sensor's attributes.
+fn discovery(stop: &StopConfig, state_topic: &str) -> (String, serde_json::Value) {
+    let id = object_id(stop);
+    let config = serde_json::json!({
+        "name": format!("{} {}", stop.route_id, stop.name),
+        "unique_id": id,
+        "object_id": id,
+        "state_topic": state_topic,
+        "value_template": "{{ value_json.next_departure }}",
+        "json_attributes_topic": state_topic,
+        "device_class": "timestamp",
+        "icon": "mdi:bus-clock",
+        "device": {
+            "identifiers": ["b60"],
+            "name": "MBTA departures",
+            "manufacturer": "b60",
+        },
+    });
+    (format!("{}/sensor/{}/config", HA_DISCOVERY_PREFIX, id), config)
+}
+
+/// Publishes handed to the MQTT client, followed by packet id until the broker
+/// acknowledges them. Discovery configs are followed as `None`.
+#[derive(Default)]
+struct Deliveries {
+    /// Publishes not yet given a packet id, in the order they were queued
+    queued: VecDeque<Option<StopSnapshot>>,
+    /// Packet id -> publish awaiting its PubAck
+    in_flight: HashMap<u16, Option<StopSnapshot>>,
+    /// Acknowledged snapshots, not yet collected by the publish loop
+    acked: Vec<StopSnapshot>,
+}
+
+impl Deliveries {
+    fn on_event(&mut self, event: &Event) {
+        match event {
+            // A packet id seen before is a retransmission after a reconnect
+            Event::Outgoing(Outgoing::Publish(pkid)) if !self.in_flight.contains_key(pkid) => {
+                let publish = self.queued.pop_front().flatten();
+                self.in_flight.insert(*pkid, publish);
+            }
+            Event::Incoming(Incoming::PubAck(ack)) => {
+                if let Some(Some(snapshot)) = self.in_flight.remove(&ack.pkid) {
+                    self.acked.push(snapshot);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// An MQTT connection whose event loop runs in the background, so publishes
+/// (and reconnects) proceed between refreshes.
+struct Mqtt {
+    client: AsyncClient,
+    deliveries: Arc<Mutex<Deliveries>>,
+}
+
+impl Mqtt {
+    /// Connects to `HOST[:PORT]`.
+    fn connect(broker: &str) -> Result<Mqtt, Box<dyn Error>> {
+        let (host, port) = match broker.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().map_err(|_| format!("invalid MQTT port: {}", port))?),
+            None => (broker, DEFAULT_MQTT_PORT),
+        };
+        let mut mqtt_options = MqttOptions::new(format!("b60-{}", std::process::id()), host, port);
+        mqtt_options.set_keep_alive(std::time::Duration::from_secs(30));
+
+        let (client, mut eventloop) = AsyncClient::new(mqtt_options, 64);
+        let deliveries = Arc::new(Mutex::new(Deliveries::default()));
+        let tracker = Arc::clone(&deliveries);
+        tokio::spawn(async move {
+            loop {
+                match eventloop.poll().await {
+                    Ok(event) => tracker.lock().unwrap().on_event(&event),
+                    Err(e) => {
+                        eprintln!("⚠️  MQTT connection error: {}", e);
+                        tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+                    }
+                }
+            }
+        });
+        Ok(Mqtt { client, deliveries })
+    }
+
+    /// Queues a retained publish without waiting for it. Fails instead of
+    /// blocking when the queue is full, as it is while the broker is unreachable.
+    fn publish(&self, topic: String, payload: String, snapshot: Option<StopSnapshot>) -> Result<(), ClientError> {
+        // Held across the hand-off so the event loop can't assign the packet id first
+        let mut deliveries = self.deliveries.lock().unwrap();
+        deliveries.queued.push_back(snapshot);
+        let result = self.client.try_publish(topic, QoS::AtLeastOnce, true, payload);
+        if result.is_err() {
+            deliveries.queued.pop_back();
+        }
+        result
+    }
+
+    /// Snapshots the broker acknowledged since the last call.
+    fn take_acked(&self) -> Vec<StopSnapshot> {
+        std::mem::take(&mut self.deliveries.lock().unwrap().acked)
+    }
+}
+
+/// Posts a snapshot to every webhook; false if any of them failed.
+async fn post_webhooks(client: &Client, webhooks: &[String], doc: &serde_json::Value) -> bool {
+    let mut ok = true;
+    for url in webhooks {
+        let result = client.post(url).json(doc).send().await.and_then(|r| r.error_for_status());
+        if let Err(e) = result {
+            eprintln!("⚠️  Webhook {} failed: {}", url, e);
+            ok = false;
+        }
+    }
+    ok
+}
+
+/// Publishes snapshots for `stops` on every refresh (or only on change with
+/// `--on-change`) until interrupted.
+pub async fn run(providers: &Providers, stops: &[StopConfig], options: &Options) -> Result<(), Box<dyn Error>> {
+    let interval = options
+        .watch
+        .unwrap_or(std::time::Duration::from_secs(DEFAULT_INTERVAL_SECS));
+    let mqtt = options.mqtt.as_deref().map(Mqtt::connect).transpose()?;
+
+    if let (Some(mqtt), true) = (&mqtt, options.ha_discovery) {
+        for stop in stops {
+            let (topic, config) = discovery(stop, &topic_for(&options.mqtt_topic, stop));
+            mqtt.publish(topic, config.to_string(), None)?;
+        }
+    }
+
+    for url in &options.webhooks {
+        eprintln!("Publishing {} stops to {}", stops.len(), url);
+    }
+    if let Some(broker) = &options.mqtt {
+        eprintln!("Publishing {} stops to mqtt://{} as {}", stops.len(), broker, options.mqtt_topic);
+    }
+
+    // Per stop, the last snapshot every webhook accepted, the last one the broker
+    // acknowledged, and the last one handed to the MQTT client
+    let mut posted: Vec<Option<serde_json::Value>> = vec![None; stops.len()];
+    let mut acked: Vec<Option<serde_json::Value>> = vec![None; stops.len()];
+    let mut queued: Vec<Option<serde_json::Value>> = vec![None; stops.len()];
+    loop {
+        let now = replay::now();
+        if let Some(mqtt) = &mqtt {
+            for (i, snapshot) in mqtt.take_acked() {
+                acked[i] = Some(snapshot);
+            }
+        }
+
+        match fetch_filtered_rows(providers, stops, &options.window, false, now).await {
+            Some(rows) => {
+                for (i, (stop, rows)) in stops.iter().zip(&rows).enumerate() {
+                    let snapshot = snapshot(stop, rows, options, now);
+                    let mut doc = snapshot.clone();
+                    doc["generated_at"] = now.to_rfc3339().into();
+
+                    // Failed snapshots are retried on the next refresh even with --on-change
+                    let unchanged = |last: &Option<serde_json::Value>| options.on_change && last.as_ref() == Some(&snapshot);
+                    if !options.webhooks.is_empty()
+                        && !unchanged(&posted[i])
+                        && post_webhooks(&providers.client, &options.webhooks, &doc).await
+                    {
+                        posted[i] = Some(snapshot.clone());
+                    }
+
+                    // A snapshot still awaiting its acknowledgement is not queued again
+                    let Some(mqtt) = &mqtt else { continue };
+                    if unchanged(&acked[i]) || unchanged(&queued[i]) {
+                        continue;
+                    }
+                    let topic = topic_for(&options.mqtt_topic, stop);
+                    match mqtt.publish(topic, doc.to_string(), Some((i, snapshot.clone()))) {
+                        Ok(()) => queued[i] = Some(snapshot),
+                        Err(e) => eprintln!("⚠️  MQTT publish failed: {}", e),
+                    }
+                }
+            }
+            None => eprintln!("⚠️  MBTA API rate limit exceeded, retrying"),
+        }
+
+        tokio::time::sleep(interval).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cli, WindowOverrides};
+    use chrono::Duration;
+    use std::time::Duration as StdDuration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    fn stop() -> StopConfig {
+        StopConfig {
+            name: "Kenmore (outbound)",
+            route_id: "60",
+            stop_id: "place-kencl",
+            direction_id: 0,
+            is_origin: true,
+            window: WindowOverrides::default(),
+            walk_minutes: None,
+            provider: "mbta",
+        }
+    }
+
+    fn row(trip_id: &str, sched_dt: DateTime<Local>) -> RowData {
+        RowData {
+            trip_id: trip_id.to_string(),
+            headsign: None,
+            sched_dt: Some(sched_dt),
+            pred_dt: None,
+            stops_away: None,
+            wheelchair_accessible: None,
+        }
+    }
+
+    #[test]
+    fn next_departure_skips_trips_that_already_left() {
+        let options = cli::parse_args(["publish", "--webhook", "http://localhost/"].map(String::from)).unwrap();
+        let now = Local::now();
+        let rows = [row("gone", now - Duration::minutes(3)), row("next", now + Duration::minutes(4))];
+        let doc = snapshot(&stop(), &rows, &options, now);
+        assert_eq!(doc["next_departure"], rows[1].sched_dt.unwrap().to_rfc3339());
+    }
+
+    /// Answers one HTTP request with `status`, returning the request it read.
+    async fn stand_in_endpoint(status: u16) -> (String, tokio::task::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let handle = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = vec![0u8; 65536];
+            let n = socket.read(&mut buf).await.unwrap();
+            let response = format!("HTTP/1.1 {} X\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status);
+            socket.write_all(response.as_bytes()).await.unwrap();
+            String::from_utf8_lossy(&buf[..n]).into_owned()
+        });
+        (url, handle)
+    }
+
+    #[tokio::test]
+    async fn webhooks_post_the_snapshot_and_report_failures() {
+        let doc = serde_json::json!({"stop_id": "place-kencl"});
+
+        let (url, request) = stand_in_endpoint(200).await;
+        assert!(post_webhooks(&Client::new(), &[url], &doc).await);
+        let request = request.await.unwrap();
+        assert!(request.starts_with("POST /hook"));
+        assert!(request.contains(r#"{"stop_id":"place-kencl"}"#));
+
+        let (url, _) = stand_in_endpoint(500).await;
+        assert!(!post_webhooks(&Client::new(), &[url], &doc).await);
+    }
+
+    /// Reads one MQTT control packet: its first header byte and its body.
+    async fn read_packet(socket: &mut TcpStream) -> Option<(u8, Vec<u8>)> {
+        let header = socket.read_u8().await.ok()?;
+        let (mut length, mut shift) = (0usize, 0);
+        loop {
+            let byte = socket.read_u8().await.ok()?;
+            length |= ((byte & 0x7f) as usize) << shift;
+            if byte & 0x80 == 0 {
+                break;
+            }
+            shift += 7;
+        }
+        let mut body = vec![0u8; length];
+        socket.read_exact(&mut body).await.ok()?;
+        Some((header, body))
+    }
+
+    /// A broker that accepts one client and acknowledges its QoS 1 publishes
+    /// only when `ack` is set.
+    async fn stand_in_broker(ack: bool) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            while let Some((header, body)) = read_packet(&mut socket).await {
+                match header >> 4 {
+                    // CONNECT -> CONNACK
+                    1 => socket.write_all(&[0x20, 0x02, 0x00, 0x00]).await.unwrap(),
+                    // PUBLISH -> PUBACK with the packet id after the topic
+                    3 if ack => {
+                        let topic_len = u16::from_be_bytes([body[0], body[1]]) as usize;
+                        let pkid = &body[2 + topic_len..4 + topic_len];
+                        socket.write_all(&[0x40, 0x02, pkid[0], pkid[1]]).await.unwrap();
+                    }
+                    // PINGREQ -> PINGRESP
+                    12 => socket.write_all(&[0xd0, 0x00]).await.unwrap(),
+                    _ => {}
+                }
+            }
+        });
+        addr
+    }
+
+    /// Collects acknowledged snapshots for up to `wait`.
+    async fn acked_within(mqtt: &Mqtt, wait: StdDuration) -> Vec<StopSnapshot> {
+        let deadline = tokio::time::Instant::now() + wait;
+        let mut acked = Vec::new();
+        while tokio::time::Instant::now() < deadline {
+            acked.extend(mqtt.take_acked());
+            if !acked.is_empty() {
+                break;
+            }
+            tokio::time::sleep(StdDuration::from_millis(20)).await;
+        }
+        acked
+    }
+
+    #[tokio::test]
+    async fn mqtt_snapshots_count_as_sent_once_acknowledged() {
+        let mqtt = Mqtt::connect(&stand_in_broker(true).await).unwrap();
+        let (config_topic, config) = discovery(&stop(), "b60/60/place-kencl/0");
+        mqtt.publish(config_topic, config.to_string(), None).unwrap();
+        let snapshot = serde_json::json!({"trips": []});
+        mqtt.publish("b60/60/place-kencl/0".into(), snapshot.to_string(), Some((3, snapshot.clone()))).unwrap();
+
+        let acked = acked_within(&mqtt, StdDuration::from_secs(5)).await;
+        assert_eq!(acked, vec![(3, snapshot)]);
+    }
+
+    #[tokio::test]
+    async fn mqtt_snapshots_without_acknowledgement_are_not_sent() {
+        let mqtt = Mqtt::connect(&stand_in_broker(false).await).unwrap();
+        let snapshot = serde_json::json!({"trips": []});
+        mqtt.publish("b60/60/place-kencl/0".into(), snapshot.to_string(), Some((0, snapshot))).unwrap();
+
+        assert!(acked_within(&mqtt, StdDuration::from_millis(500)).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn mqtt_publish_fails_instead_of_blocking_without_a_broker() {
+        // Nothing listens on a port just released
+        let addr = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap().to_string();
+        let mqtt = Mqtt::connect(&addr).unwrap();
+        let failed = (0..100).any(|_| mqtt.publish("b60/test".into(), "{}".into(), None).is_err());
+        assert!(failed);
+    }
+}
@@ -0,0 +1,410 @@
+//! `b60 record`: periodically stores schedules, predictions and vehicle positions
+//! in SQLite and infers when each trip actually left the stop.
+
+use chrono::{DateTime, Local, NaiveDate};
+use reqwest::Client;
+use rusqlite::{params, Connection, OpenFlags};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::PathBuf;
+
+use crate::cli::{Options, DEFAULT_INTERVAL_SECS};
+use crate::mbta::{api_get_all, resolve_parents, service_date, ApiResponse, OptionalDataWrapper, Resource};
+use crate::provider::{Providers, DEFAULT_PROVIDER};
+use crate::replay;
+use crate::{fetch_stops, parse_time, RowData, StopConfig};
+
+/// A prediction withdrawn more than this many seconds before its predicted time
+/// is treated as lost rather than as a departure.
+const DEPARTED_TOLERANCE_SECS: i64 = 120;
+/// Vehicle positions older than this are not matched to trips, since trip ids
+/// repeat across service days.
+const VEHICLE_LOOKBACK_SECS: i64 = 6 * 3600;
+
+/// Sets `departed` from vehicle positions: the first position of the trip's
+/// vehicle away from the stop after its last position at the stop.
+const DEPARTED_FROM_VEHICLES: &str = "
+UPDATE trips SET departed = (
+    SELECT min(v.updated_at) FROM vehicles v
+    WHERE v.trip_id = trips.trip_id
+      AND v.stop_id IS NOT trips.stop_id
+      AND v.updated_at > (
+          SELECT max(w.updated_at) FROM vehicles w
+          WHERE w.trip_id = trips.trip_id AND w.stop_id = trips.stop_id AND w.updated_at >= ?2
+      )
+)
+WHERE stop_id = ?1 AND departed IS NULL AND scheduled >= ?2";
+
+/// Times are unix seconds. Predictions are only appended when they change, and
+/// vehicles only when the API reports a new position.
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS trips (
+    service_date TEXT NOT NULL,
+    stop_id      TEXT NOT NULL,
+    trip_id      TEXT NOT NULL,
+    route_id     TEXT NOT NULL,
+    direction_id INTEGER NOT NULL,
+    headsign     TEXT,
+    scheduled    INTEGER,
+    departed     INTEGER,
+    PRIMARY KEY (service_date, stop_id, trip_id)
+) WITHOUT ROWID;
+CREATE INDEX IF NOT EXISTS trips_by_stop ON trips (stop_id, scheduled);
+
+CREATE TABLE IF NOT EXISTS predictions (
+    service_date TEXT NOT NULL,
+    stop_id      TEXT NOT NULL,
+    trip_id      TEXT NOT NULL,
+    observed_at  INTEGER NOT NULL,
+    predicted    INTEGER,
+    stops_away   INTEGER,
+    PRIMARY KEY (service_date, stop_id, trip_id, observed_at)
+) WITHOUT ROWID;
+
+CREATE TABLE IF NOT EXISTS vehicles (
+    vehicle_id   TEXT NOT NULL,
+    updated_at   INTEGER NOT NULL,
+    route_id     TEXT NOT NULL,
+    direction_id INTEGER NOT NULL,
+    trip_id      TEXT,
+    stop_id      TEXT,
+    status       TEXT,
+    latitude     REAL,
+    longitude    REAL,
+    PRIMARY KEY (vehicle_id, updated_at)
+) WITHOUT ROWID;
+";
+
+#[derive(Debug, Deserialize)]
+struct VehicleAttributes {
+    current_status: Option<String>,
+    latitude: Option<f64>,
+    longitude: Option<f64>,
+    updated_at: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct VehicleRelationships {
+    trip: Option<OptionalDataWrapper>,
+    stop: Option<OptionalDataWrapper>,
+}
+
+/// The last prediction recorded for a trip at a stop.
+struct LivePrediction {
+    service_date: NaiveDate,
+    predicted: DateTime<Local>,
+    stops_away: Option<i32>,
+    seen_at: DateTime<Local>,
+}
+
+/// `$XDG_DATA_HOME/b60`, falling back to `~/.local/share/b60`.
+pub fn data_dir() -> PathBuf {
+    let data_home = std::env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/share")))
+        .unwrap_or_default();
+    data_home.join("b60")
+}
+
+/// `history.db` in the data directory.
+pub fn default_db_path() -> PathBuf {
+    data_dir().join("history.db")
+}
+
+/// The database at `--db`, or the default path.
+fn db_path(options: &Options) -> PathBuf {
+    options.db.clone().map(PathBuf::from).unwrap_or_else(default_db_path)
+}
+
+/// Opens the database at `--db` (or the default path) and creates the schema.
+pub fn open_db(options: &Options) -> Result<Connection, Box<dyn Error>> {
+    let path = db_path(options);
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir)?;
+    }
+    let db = Connection::open(&path)?;
+    db.execute_batch(SCHEMA)?;
+    Ok(db)
+}
+
+/// Opens an existing database read-only, for `report` and `--confidence`.
+pub fn open_db_read_only(options: &Options) -> Result<Connection, Box<dyn Error>> {
+    let path = db_path(options);
+    if !path.exists() {
+        return Err(format!("no history at {}; run `b60 record` first", path.display()).into());
+    }
+    Ok(Connection::open_with_flags(&path, OpenFlags::SQLITE_OPEN_READ_ONLY)?)
+}
+
+/// The stop a vehicle reports, as `stops` name it: vehicles report platforms,
+/// so a platform of a monitored parent station maps to the station.
+fn vehicle_stop(stop_id: String, stops: &[StopConfig], stop_parents: &HashMap<String, String>) -> String {
+    if stops.iter().any(|s| s.stop_id == stop_id) {
+        return stop_id;
+    }
+    stop_parents.get(&stop_id).cloned().unwrap_or(stop_id)
+}
+
+/// Stores vehicle positions for every route/direction among the MBTA `stops`.
+/// `stop_parents` caches platform -> parent station lookups across polls.
+async fn record_vehicles(
+    client: &Client,
+    db: &Connection,
+    stops: &[StopConfig],
+    stop_parents: &mut HashMap<String, String>,
+) -> Result<(), Box<dyn Error>> {
+    let mut groups: Vec<(&str, i32)> = stops
+        .iter()
+        .filter(|s| s.provider == DEFAULT_PROVIDER)
+        .map(|s| (s.route_id, s.direction_id))
+        .collect();
+    groups.sort();
+    groups.dedup();
+
+    for (route_id, direction_id) in groups {
+        let params = [
+            ("filter[route]", route_id.to_string()),
+            ("filter[direction_id]", direction_id.to_string()),
+        ];
+        let vehicles: ApiResponse<Resource<VehicleAttributes, VehicleRelationships>> =
+            api_get_all(client, "/vehicles", &params, "Vehicle").await?;
+
+        let id_of = |rel: &Option<OptionalDataWrapper>| rel.as_ref().and_then(|r| r.data.as_ref()).map(|d| d.id.clone());
+        let stop_ids: Vec<String> = vehicles.data.iter().filter_map(|v| id_of(&v.relationships.stop)).collect();
+        resolve_parents(client, &stop_ids, stop_parents).await;
+        for v in vehicles.data {
+            let Some(updated_at) = parse_time(v.attributes.updated_at) else { continue };
+            db.execute(
+                "INSERT OR IGNORE INTO vehicles VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                params![
+                    v.id,
+                    updated_at.timestamp(),
+                    route_id,
+                    direction_id,
+                    id_of(&v.relationships.trip),
+                    id_of(&v.relationships.stop).map(|id| vehicle_stop(id, stops, stop_parents)),
+                    v.attributes.current_status,
+                    v.attributes.latitude,
+                    v.attributes.longitude,
+                ],
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Records one poll of schedules and predictions. A trip has departed once its
+/// vehicle is seen away from the stop after being at it. Without vehicle
+/// positions, a prediction the API withdraws close to its predicted time while
+/// the trip is still scheduled in the window means the vehicle has left: the
+/// departure is taken as the last predicted time, clamped between the last poll
+/// that saw it and now.
+fn record_rows(
+    db: &mut Connection,
+    stop: &StopConfig,
+    rows: &[RowData],
+    live: &mut HashMap<(&'static str, String), LivePrediction>,
+    now: DateTime<Local>,
+) -> Result<(), Box<dyn Error>> {
+    let tx = db.transaction()?;
+
+    for row in rows {
+        let Some(t) = row.sched_dt.or(row.pred_dt) else { continue };
+        let date = service_date(t);
+        tx.execute(
+            "INSERT INTO trips (service_date, stop_id, trip_id, route_id, direction_id, headsign, scheduled)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+             ON CONFLICT DO UPDATE SET
+                headsign = coalesce(excluded.headsign, headsign),
+                scheduled = coalesce(excluded.scheduled, scheduled)",
+            params![
+                date.to_string(),
+                stop.stop_id,
+                row.trip_id,
+                stop.route_id,
+                stop.direction_id,
+                row.headsign,
+                row.sched_dt.map(|t| t.timestamp()),
+            ],
+        )?;
+
+        let Some(pred) = row.pred_dt else { continue };
+        let key = (stop.stop_id, row.trip_id.clone());
+        let changed = live
+            .get(&key)
+            .is_none_or(|last| last.predicted != pred || last.stops_away != row.stops_away);
+        if changed {
+            tx.execute(
+                "INSERT OR REPLACE INTO predictions VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![date.to_string(), stop.stop_id, row.trip_id, now.timestamp(), pred.timestamp(), row.stops_away],
+            )?;
+        }
+        live.insert(
+            key,
+            LivePrediction { service_date: date, predicted: pred, stops_away: row.stops_away, seen_at: now },
+        );
+    }
+
+    tx.execute(DEPARTED_FROM_VEHICLES, params![stop.stop_id, now.timestamp() - VEHICLE_LOOKBACK_SECS])?;
+
+    // Predictions for this stop that were not refreshed in this poll were withdrawn
+    let withdrawn: Vec<(&'static str, String)> = live
+        .iter()
+        .filter(|((stop_id, _), last)| *stop_id == stop.stop_id && last.seen_at < now)
+        .map(|(key, _)| key.clone())
+        .collect();
+    for key in withdrawn {
+        let Some(last) = live.remove(&key) else { continue };
+        let date = last.service_date.to_string();
+        tx.execute(
+            "INSERT OR REPLACE INTO predictions VALUES (?1, ?2, ?3, ?4, NULL, NULL)",
+            params![date, key.0, key.1, now.timestamp()],
+        )?;
+
+        // A trip that left the window altogether says nothing about when it departed
+        let scheduled = rows.iter().any(|r| r.trip_id == key.1 && r.sched_dt.is_some());
+        let predicted = last.predicted;
+        if scheduled && predicted.signed_duration_since(now).num_seconds() <= DEPARTED_TOLERANCE_SECS {
+            let departed = predicted.clamp(last.seen_at, now);
+            tx.execute(
+                "UPDATE trips SET departed = ?1
+                 WHERE service_date = ?2 AND stop_id = ?3 AND trip_id = ?4 AND departed IS NULL",
+                params![departed.timestamp(), date, key.0, key.1],
+            )?;
+        }
+    }
+
+    tx.commit()?;
+    Ok(())
+}
+
+/// Polls `stops` and appends to the history database until interrupted.
+pub async fn run(providers: &Providers, stops: &[StopConfig], options: &Options) -> Result<(), Box<dyn Error>> {
+    let interval = options
+        .watch
+        .unwrap_or(std::time::Duration::from_secs(DEFAULT_INTERVAL_SECS));
+    let mut db = open_db(options)?;
+    let mut live: HashMap<(&'static str, String), LivePrediction> = HashMap::new();
+    let mut stop_parents: HashMap<String, String> = HashMap::new();
+
+    eprintln!(
+        "Recording {} stops every {}s to {}",
+        stops.len(),
+        interval.as_secs(),
+        db.path().unwrap_or("database")
+    );
+
+    loop {
+        let now = replay::now();
+        let results = fetch_stops(providers, stops, &options.window, now).await;
+        // Positions first, so this poll's departures can be read from them
+        if let Err(e) = record_vehicles(&providers.client, &db, stops, &mut stop_parents).await {
+            eprintln!("⚠️  Error fetching vehicles: {}", e);
+        }
+
+        for (stop, result) in stops.iter().zip(results) {
+            // A failed fetch must not look like every prediction being withdrawn
+            match result {
+                Ok(rows) => {
+                    if let Err(e) = record_rows(&mut db, stop, &rows, &mut live, now) {
+                        eprintln!("⚠️  Failed to record {}: {}", stop.name, e);
+                    }
+                }
+                Err(e) => eprintln!("⚠️  Error fetching {} data: {}", stop.name, e),
+            }
+        }
+
+        tokio::time::sleep(interval).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration, TimeZone};
+
+    fn stop() -> StopConfig {
+        StopConfig {
+            name: "Test Stop",
+            route_id: "1",
+            stop_id: "110",
+            direction_id: 0,
+            is_origin: false,
+            window: Default::default(),
+            walk_minutes: None,
+            provider: DEFAULT_PROVIDER,
+        }
+    }
+
+    fn row(trip_id: &str, sched_dt: Option<DateTime<Local>>, pred_dt: Option<DateTime<Local>>) -> RowData {
+        RowData {
+            trip_id: trip_id.to_string(),
+            headsign: None,
+            sched_dt,
+            pred_dt,
+            stops_away: None,
+            wheelchair_accessible: None,
+        }
+    }
+
+    fn departed(db: &Connection, trip_id: &str) -> Option<i64> {
+        db.query_row("SELECT departed FROM trips WHERE trip_id = ?1", [trip_id], |r| r.get(0))
+            .unwrap()
+    }
+
+    fn db() -> Connection {
+        let db = Connection::open_in_memory().unwrap();
+        db.execute_batch(SCHEMA).unwrap();
+        db
+    }
+
+    #[test]
+    fn departure_is_read_from_vehicle_positions() {
+        let t0 = Local.with_ymd_and_hms(2026, 10, 14, 8, 0, 0).unwrap();
+        // Vehicles report platforms; a monitored station is matched through its platforms
+        let stop_parents: HashMap<String, String> =
+            [("70150", "place-kencl"), ("70151", "place-kencl"), ("70148", "place-bland")]
+                .into_iter()
+                .map(|(platform, station)| (platform.to_string(), station.to_string()))
+                .collect();
+        let station = StopConfig { stop_id: "place-kencl", ..stop() };
+        let cases = [(stop(), ["109", "110", "111"]), (station, ["70152", "70150", "70148"])];
+
+        for (stop, positions) in cases {
+            let mut db = db();
+            let mut live = HashMap::new();
+            record_rows(&mut db, &stop, &[row("T1", Some(t0), Some(t0))], &mut live, t0 - Duration::minutes(5)).unwrap();
+
+            let stops = [stop];
+            for (secs, stop_id) in [-120, -30, 40].into_iter().zip(positions) {
+                db.execute(
+                    "INSERT INTO vehicles (vehicle_id, updated_at, route_id, direction_id, trip_id, stop_id)
+                     VALUES ('y1', ?1, '1', 0, 'T1', ?2)",
+                    params![t0.timestamp() + secs, vehicle_stop(stop_id.to_string(), &stops, &stop_parents)],
+                )
+                .unwrap();
+            }
+            // The prediction is still live, but the vehicle has moved on
+            let now = t0 + Duration::minutes(1);
+            record_rows(&mut db, &stops[0], &[row("T1", Some(t0), Some(t0))], &mut live, now).unwrap();
+            assert_eq!(departed(&db, "T1"), Some(t0.timestamp() + 40), "{}", stops[0].stop_id);
+        }
+    }
+
+    #[test]
+    fn withdrawn_prediction_counts_only_while_scheduled_in_window() {
+        let mut db = db();
+        let stop = stop();
+        let t0 = Local.with_ymd_and_hms(2026, 10, 14, 8, 0, 0).unwrap();
+        let mut live = HashMap::new();
+        let rows = [row("T1", Some(t0), Some(t0)), row("T2", Some(t0), Some(t0))];
+        record_rows(&mut db, &stop, &rows, &mut live, t0 - Duration::minutes(1)).unwrap();
+
+        // T1 is still scheduled without its prediction; T2 left the window entirely
+        let now = t0 + Duration::minutes(1);
+        record_rows(&mut db, &stop, &[row("T1", Some(t0), None)], &mut live, now).unwrap();
+        assert_eq!(departed(&db, "T1"), Some(t0.timestamp()));
+        assert_eq!(departed(&db, "T2"), None);
+    }
+}
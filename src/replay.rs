@@ -0,0 +1,246 @@
+//! `--record` and `--replay`: every MBTA API and GTFS-realtime exchange of a
+//! refresh is saved to a directory, tagged with the refresh's `now`, and later served back with the
+//! clock frozen at that `now`, so a past screen can be reproduced exactly. Also
+//! owns `--at`, which runs the clock from another time.
+
+use chrono::{DateTime, Duration, Local, Utc};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use crate::cli::Options;
+
+static SESSION: Mutex<Option<Session>> = Mutex::new(None);
+
+/// A recorded (status, body), if replaying.
+pub type Replayed<T> = Option<Result<(u16, T), Box<dyn Error>>>;
+
+/// One saved request and its response, stored as `<now>-<seq>.json` with `now`
+/// in UTC.
+#[derive(Serialize, Deserialize)]
+struct Exchange {
+    /// The refresh the request was made for (RFC 3339)
+    now: String,
+    url: String,
+    query: Vec<(String, String)>,
+    status: u16,
+    /// The response as JSON when it parses, otherwise as a string
+    body: serde_json::Value,
+    /// "hex" when `body` is a binary response (GTFS-realtime) encoded as a hex string
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    encoding: Option<String>,
+}
+
+impl Exchange {
+    fn body_bytes(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        match (&self.body, self.encoding.as_deref()) {
+            (serde_json::Value::String(s), Some("hex")) => from_hex(s).ok_or_else(|| "invalid hex body".into()),
+            (_, Some(other)) => Err(format!("unknown body encoding {}", other).into()),
+            (serde_json::Value::String(s), None) => Ok(s.clone().into_bytes()),
+            (other, None) => Ok(other.to_string().into_bytes()),
+        }
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    (0..s.len())
+        .step_by(2)
+        .map(|i| s.get(i..i + 2).and_then(|h| u8::from_str_radix(h, 16).ok()))
+        .collect()
+}
+
+/// The exchanges of one refresh.
+struct Frame {
+    now: DateTime<Local>,
+    exchanges: Vec<Exchange>,
+    served: Vec<bool>,
+}
+
+enum Session {
+    /// `--at` without `--replay`: the clock runs from the given time
+    Simulated {
+        offset: Duration,
+    },
+    Record {
+        dir: PathBuf,
+        now: DateTime<Local>,
+        seq: usize,
+    },
+    Replay {
+        frames: Vec<Frame>,
+        current: usize,
+        /// Whether the current frame has served a response yet
+        started: bool,
+    },
+}
+
+fn load_frames(dir: &str) -> Result<Vec<Frame>, Box<dyn Error>> {
+    let mut paths: Vec<PathBuf> = std::fs::read_dir(dir)
+        .map_err(|e| format!("{}: {}", dir, e))?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|p| p.extension().is_some_and(|ext| ext == "json"))
+        .collect();
+    // Names start with the refresh time and a sequence number, so this is recording order
+    paths.sort();
+
+    let mut frames: Vec<Frame> = Vec::new();
+    for path in paths {
+        let text = std::fs::read_to_string(&path)?;
+        let exchange: Exchange = serde_json::from_str(&text).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let now = DateTime::parse_from_rfc3339(&exchange.now)
+            .map_err(|e| format!("{}: {}", path.display(), e))?
+            .with_timezone(&Local);
+        match frames.last_mut().filter(|f| f.now == now) {
+            Some(frame) => {
+                frame.exchanges.push(exchange);
+                frame.served.push(false);
+            }
+            None => frames.push(Frame { now, exchanges: vec![exchange], served: vec![false] }),
+        }
+    }
+    if frames.is_empty() {
+        return Err(format!("{}: no recorded exchanges", dir).into());
+    }
+    Ok(frames)
+}
+
+/// Starts recording or replaying for `--record`/`--replay`, and sets the clock for `--at`.
+pub fn init(options: &Options) -> Result<(), Box<dyn Error>> {
+    let session = if let Some(dir) = &options.record_dir {
+        std::fs::create_dir_all(dir)?;
+        Some(Session::Record { dir: PathBuf::from(dir), now: Local::now(), seq: 0 })
+    } else if let Some(dir) = &options.replay_dir {
+        let frames = load_frames(dir)?;
+        // The last refresh recorded at or before `--at`, or the first one
+        let current = options
+            .at
+            .and_then(|at| frames.iter().rposition(|f| f.now <= at))
+            .unwrap_or(0);
+        Some(Session::Replay { frames, current, started: false })
+    } else {
+        options.at.map(|at| Session::Simulated { offset: at - Local::now() })
+    };
+    *SESSION.lock().unwrap() = session;
+    Ok(())
+}
+
+/// The time a refresh is computed for. When replaying, this is the recorded
+/// refresh's time, moving on to the next recorded refresh once the current one
+/// has served its responses; after the last one it stays there.
+pub fn now() -> DateTime<Local> {
+    let mut session = SESSION.lock().unwrap();
+    match session.as_mut() {
+        None => Local::now(),
+        Some(Session::Simulated { offset }) => Local::now() + *offset,
+        Some(Session::Record { now, .. }) => {
+            *now = Local::now();
+            *now
+        }
+        Some(Session::Replay { frames, current, started }) => {
+            if *started && *current + 1 < frames.len() {
+                *current += 1;
+                *started = false;
+            }
+            frames[*current].now
+        }
+    }
+}
+
+/// Whether the clock was moved with `--at`, so live predictions (which are for
+/// the real time) must not be used.
+pub fn simulated() -> bool {
+    matches!(*SESSION.lock().unwrap(), Some(Session::Simulated { .. }))
+}
+
+/// The recorded (status, body) for a request when replaying, or None when not
+/// replaying. Repeated identical requests get their responses in recorded
+/// order, and the last one again once those run out.
+pub fn replayed(url: &str, query: &[(&str, String)]) -> Replayed<String> {
+    replayed_bytes(url, query).map(|r| r.map(|(status, body)| (status, String::from_utf8_lossy(&body).into_owned())))
+}
+
+/// Like `replayed`, for binary responses.
+pub fn replayed_bytes(url: &str, query: &[(&str, String)]) -> Replayed<Vec<u8>> {
+    let mut session = SESSION.lock().unwrap();
+    let Some(Session::Replay { frames, current, started }) = session.as_mut() else {
+        return None;
+    };
+    *started = true;
+    let frame = &mut frames[*current];
+
+    let matches = |e: &Exchange| {
+        e.url == url
+            && e.query.len() == query.len()
+            && e.query.iter().zip(query).all(|((k, v), (qk, qv))| k == qk && v == qv)
+    };
+    let index = (0..frame.exchanges.len())
+        .find(|&i| !frame.served[i] && matches(&frame.exchanges[i]))
+        .or_else(|| (0..frame.exchanges.len()).rev().find(|&i| matches(&frame.exchanges[i])));
+    let Some(index) = index else {
+        return Some(Err(format!("no recorded response for {} {:?} at {}", url, query, frame.now.to_rfc3339()).into()));
+    };
+    frame.served[index] = true;
+
+    let exchange = &frame.exchanges[index];
+    Some(exchange.body_bytes().map(|body| (exchange.status, body)))
+}
+
+/// Saves an exchange when recording. Failures are reported but do not fail the request.
+pub fn save(url: &str, query: &[(&str, String)], status: u16, body: &str) {
+    let body = serde_json::from_str(body).unwrap_or_else(|_| serde_json::Value::String(body.to_string()));
+    save_exchange(url, query, status, body, None);
+}
+
+/// Like `save`, for binary responses.
+pub fn save_bytes(url: &str, query: &[(&str, String)], status: u16, body: &[u8]) {
+    save_exchange(url, query, status, serde_json::Value::String(to_hex(body)), Some("hex"));
+}
+
+fn save_exchange(url: &str, query: &[(&str, String)], status: u16, body: serde_json::Value, encoding: Option<&str>) {
+    let mut session = SESSION.lock().unwrap();
+    let Some(Session::Record { dir, now, seq }) = session.as_mut() else {
+        return;
+    };
+    *seq += 1;
+
+    let exchange = Exchange {
+        now: now.to_rfc3339(),
+        url: url.to_string(),
+        query: query.iter().map(|(k, v)| (k.to_string(), v.clone())).collect(),
+        status,
+        body,
+        encoding: encoding.map(str::to_string),
+    };
+    let path = dir.join(format!("{}-{:04}.json", now.with_timezone(&Utc).format("%Y%m%dT%H%M%SZ"), seq));
+    let result = serde_json::to_string_pretty(&exchange)
+        .map_err(|e| e.to_string())
+        .and_then(|json| std::fs::write(&path, json).map_err(|e| e.to_string()));
+    if let Err(e) = result {
+        eprintln!("⚠️  Failed to record {}: {}", path.display(), e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn binary_bodies_round_trip_as_hex() {
+        let body = [0x0a_u8, 0x00, 0xff, 0x12];
+        let exchange = Exchange {
+            now: String::new(),
+            url: String::new(),
+            query: Vec::new(),
+            status: 200,
+            body: serde_json::Value::String(to_hex(&body)),
+            encoding: Some("hex".to_string()),
+        };
+        assert_eq!(exchange.body_bytes().unwrap(), body);
+        assert_eq!(from_hex("0a0"), None);
+    }
+}
@@ -0,0 +1,495 @@
+//! `b60 report`: schedule adherence and headway statistics from the history
+//! written by `b60 record`.
+
+use chrono::{DateTime, Datelike, Duration, Local, NaiveDate, TimeZone, Timelike};
+use rusqlite::{params, Connection};
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::IsTerminal;
+
+use crate::cli::{Options, OutputFormat};
+use crate::accuracy::{self, Accuracy};
+use crate::{format_delay, paint, record, DelayStatus, StopConfig, STYLE_BOLD, STYLE_DIM};
+
+/// Number of worst trips listed per stop.
+const WORST_TRIPS: usize = 10;
+const WEEKDAYS: [&str; 7] = ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"];
+
+/// A recorded trip at one stop that was scheduled within the report period.
+struct TripRecord {
+    service_date: NaiveDate,
+    trip_id: String,
+    headsign: Option<String>,
+    scheduled: DateTime<Local>,
+    departed: Option<DateTime<Local>>,
+    /// Gap to the previous scheduled trip of the same service day
+    scheduled_headway: Option<i64>,
+    /// Gap to the previous observed departure of the same service day
+    actual_headway: Option<i64>,
+}
+
+impl TripRecord {
+    fn delay_seconds(&self) -> Option<i64> {
+        self.departed.map(|d| d.signed_duration_since(self.scheduled).num_seconds())
+    }
+}
+
+/// Statistics over a group of trips, e.g. every trip scheduled in the 7am hour.
+struct Bucket {
+    group: &'static str,
+    key: String,
+    scheduled: usize,
+    observed: usize,
+    early: usize,
+    on_time: usize,
+    late: usize,
+    median_delay: Option<i64>,
+    p90_delay: Option<i64>,
+    median_scheduled_headway: Option<i64>,
+    median_actual_headway: Option<i64>,
+    p90_actual_headway: Option<i64>,
+}
+
+impl Bucket {
+    fn pct(&self, n: usize) -> Option<f64> {
+        (self.observed > 0).then(|| n as f64 * 100.0 / self.observed as f64)
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "key": self.key,
+            "scheduled": self.scheduled,
+            "observed": self.observed,
+            "on_time_pct": self.pct(self.on_time),
+            "early_pct": self.pct(self.early),
+            "late_pct": self.pct(self.late),
+            "median_delay_seconds": self.median_delay,
+            "p90_delay_seconds": self.p90_delay,
+            "median_scheduled_headway_seconds": self.median_scheduled_headway,
+            "median_actual_headway_seconds": self.median_actual_headway,
+            "p90_actual_headway_seconds": self.p90_actual_headway,
+        })
+    }
+}
+
+/// Nearest-rank percentile of an unsorted sample.
+pub fn percentile(mut values: Vec<i64>, p: f64) -> Option<i64> {
+    if values.is_empty() {
+        return None;
+    }
+    values.sort_unstable();
+    let rank = ((p * values.len() as f64).ceil() as usize).clamp(1, values.len());
+    Some(values[rank - 1])
+}
+
+fn bucket(group: &'static str, key: String, trips: &[&TripRecord]) -> Bucket {
+    let delays: Vec<i64> = trips.iter().filter_map(|t| t.delay_seconds()).collect();
+    let count = |status: fn(&DelayStatus) -> bool| {
+        delays.iter().filter(|d| status(&DelayStatus::from_delay(**d))).count()
+    };
+    let actual_headways: Vec<i64> = trips.iter().filter_map(|t| t.actual_headway).collect();
+
+    Bucket {
+        group,
+        key,
+        scheduled: trips.len(),
+        observed: delays.len(),
+        early: count(|s| matches!(s, DelayStatus::Early)),
+        on_time: count(|s| matches!(s, DelayStatus::OnTime)),
+        late: count(|s| matches!(s, DelayStatus::Late | DelayStatus::VeryLate)),
+        median_delay: percentile(delays.clone(), 0.5),
+        p90_delay: percentile(delays, 0.9),
+        median_scheduled_headway: percentile(trips.iter().filter_map(|t| t.scheduled_headway).collect(), 0.5),
+        median_actual_headway: percentile(actual_headways.clone(), 0.5),
+        p90_actual_headway: percentile(actual_headways, 0.9),
+    }
+}
+
+fn from_unix(secs: i64) -> Option<DateTime<Local>> {
+    Local.timestamp_opt(secs, 0).single()
+}
+
+/// Loads the stop's past trips since `since` and fills in their headways.
+fn load_trips(db: &Connection, stop: &StopConfig, since: DateTime<Local>, now: DateTime<Local>) -> Result<Vec<TripRecord>, Box<dyn Error>> {
+    let mut stmt = db.prepare(
+        "SELECT service_date, trip_id, headsign, scheduled, departed FROM trips
+         WHERE stop_id = ?1 AND route_id = ?2 AND scheduled BETWEEN ?3 AND ?4
+         ORDER BY scheduled",
+    )?;
+    let rows = stmt.query_map(
+        params![stop.stop_id, stop.route_id, since.timestamp(), now.timestamp()],
+        |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, Option<String>>(2)?,
+                row.get::<_, i64>(3)?,
+                row.get::<_, Option<i64>>(4)?,
+            ))
+        },
+    )?;
+
+    let mut trips = Vec::new();
+    for row in rows {
+        let (service_date, trip_id, headsign, scheduled, departed) = row?;
+        let (Ok(service_date), Some(scheduled)) = (service_date.parse(), from_unix(scheduled)) else { continue };
+        trips.push(TripRecord {
+            service_date,
+            trip_id,
+            headsign,
+            scheduled,
+            departed: departed.and_then(from_unix),
+            scheduled_headway: None,
+            actual_headway: None,
+        });
+    }
+
+    // Trips are ordered by scheduled time; headways never span service days
+    let mut last_scheduled: HashMap<NaiveDate, DateTime<Local>> = HashMap::new();
+    for trip in trips.iter_mut() {
+        if let Some(prev) = last_scheduled.insert(trip.service_date, trip.scheduled) {
+            trip.scheduled_headway = Some(trip.scheduled.signed_duration_since(prev).num_seconds());
+        }
+    }
+    let mut by_departure: Vec<usize> = (0..trips.len()).filter(|i| trips[*i].departed.is_some()).collect();
+    by_departure.sort_by_key(|i| trips[*i].departed);
+    let mut last_departed: HashMap<NaiveDate, DateTime<Local>> = HashMap::new();
+    for i in by_departure {
+        let Some(departed) = trips[i].departed else { continue };
+        if let Some(prev) = last_departed.insert(trips[i].service_date, departed) {
+            trips[i].actual_headway = Some(departed.signed_duration_since(prev).num_seconds());
+        }
+    }
+
+    Ok(trips)
+}
+
+/// The overall bucket, then one per hour of day and one per weekday (service day).
+fn buckets(trips: &[TripRecord]) -> Vec<Bucket> {
+    let all: Vec<&TripRecord> = trips.iter().collect();
+    let mut buckets = vec![bucket("all", "all".to_string(), &all)];
+
+    for hour in 0..24 {
+        let group: Vec<&TripRecord> = trips.iter().filter(|t| t.scheduled.hour() == hour).collect();
+        if !group.is_empty() {
+            buckets.push(bucket("hour", format!("{:02}", hour), &group));
+        }
+    }
+    for (day, name) in WEEKDAYS.iter().enumerate() {
+        let group: Vec<&TripRecord> = trips
+            .iter()
+            .filter(|t| t.service_date.weekday().num_days_from_monday() as usize == day)
+            .collect();
+        if !group.is_empty() {
+            buckets.push(bucket("weekday", name.to_string(), &group));
+        }
+    }
+    buckets
+}
+
+fn worst_trips(trips: &[TripRecord]) -> Vec<&TripRecord> {
+    let mut late: Vec<&TripRecord> = trips.iter().filter(|t| t.delay_seconds().is_some()).collect();
+    late.sort_by_key(|t| std::cmp::Reverse(t.delay_seconds()));
+    late.truncate(WORST_TRIPS);
+    late
+}
+
+/// "+1.5m", or "–" when there is no data.
+fn minutes(secs: Option<i64>, signed: bool) -> String {
+    match secs {
+        // Adding 0.0 turns a rounded -0.0 into 0.0
+        Some(s) if signed => format!("{:+.1}m", (s as f64 / 6.0).round() / 10.0 + 0.0),
+        Some(s) => format!("{:.0}m", s as f64 / 60.0),
+        None => "–".to_string(),
+    }
+}
+
+fn percent(pct: Option<f64>) -> String {
+    pct.map_or_else(|| "–".to_string(), |p| format!("{:.0}%", p))
+}
+
+fn print_table(stop: &StopConfig, trips: &[TripRecord], buckets: &[Bucket], days: i64, color: bool) {
+    let summary = &buckets[0];
+    println!(
+        "{}",
+        paint(&format!("{} — {}, last {} days", stop.name, stop.route_id, days), STYLE_BOLD, color)
+    );
+    if summary.scheduled == 0 {
+        println!("{}", paint("  No recorded trips", STYLE_DIM, color));
+        println!();
+        return;
+    }
+    println!(
+        "  {} of {} scheduled trips observed · {} on time · {} early · {} late",
+        summary.observed,
+        summary.scheduled,
+        percent(summary.pct(summary.on_time)),
+        percent(summary.pct(summary.early)),
+        percent(summary.pct(summary.late))
+    );
+
+    for (group, title) in [("hour", "Hour"), ("weekday", "Day")] {
+        println!();
+        let header = format!(
+            "  {:<6}{:>7}{:>9}{:>9}{:>9}   {}",
+            title, "Seen", "On time", "Median", "p90", "Headway sched / actual (p90)"
+        );
+        println!("{}", paint(&header, STYLE_BOLD, color));
+        for b in buckets.iter().filter(|b| b.group == group) {
+            println!(
+                "  {:<6}{:>7}{:>9}{:>9}{:>9}   {} / {} ({})",
+                b.key,
+                format!("{}/{}", b.observed, b.scheduled),
+                percent(b.pct(b.on_time)),
+                minutes(b.median_delay, true),
+                minutes(b.p90_delay, true),
+                minutes(b.median_scheduled_headway, false),
+                minutes(b.median_actual_headway, false),
+                minutes(b.p90_actual_headway, false)
+            );
+        }
+    }
+
+    let worst = worst_trips(trips);
+    if !worst.is_empty() {
+        println!();
+        println!("{}", paint("  Worst trips", STYLE_BOLD, color));
+        for t in worst {
+            let delay = t.delay_seconds().unwrap_or(0);
+            let status = DelayStatus::from_delay(delay);
+            println!(
+                "  {} {}  {:<14} {:<22} {}",
+                t.scheduled.format("%Y-%m-%d %a"),
+                t.scheduled.format("%H:%M"),
+                t.trip_id,
+                t.headsign.as_deref().unwrap_or(""),
+                paint(&format_delay(delay), status.color(), color)
+            );
+        }
+    }
+    println!();
+}
+
+fn stop_report_json(stop: &StopConfig, trips: &[TripRecord], buckets: &[Bucket]) -> serde_json::Value {
+    let group = |name: &str| -> Vec<serde_json::Value> {
+        buckets.iter().filter(|b| b.group == name).map(Bucket::to_json).collect()
+    };
+    let worst: Vec<serde_json::Value> = worst_trips(trips)
+        .iter()
+        .map(|t| {
+            serde_json::json!({
+                "service_date": t.service_date.to_string(),
+                "trip_id": t.trip_id,
+                "headsign": t.headsign,
+                "scheduled_time": t.scheduled.to_rfc3339(),
+                "departed_time": t.departed.map(|d| d.to_rfc3339()),
+                "delay_seconds": t.delay_seconds(),
+            })
+        })
+        .collect();
+
+    serde_json::json!({
+        "name": stop.name,
+        "route_id": stop.route_id,
+        "stop_id": stop.stop_id,
+        "direction_id": stop.direction_id,
+        "summary": buckets[0].to_json(),
+        "by_hour": group("hour"),
+        "by_weekday": group("weekday"),
+        "worst_trips": worst,
+    })
+}
+
+const CSV_HEADER: &str = "stop_id,route_id,group,key,scheduled,observed,on_time_pct,early_pct,late_pct,\
+median_delay_seconds,p90_delay_seconds,median_scheduled_headway_seconds,\
+median_actual_headway_seconds,p90_actual_headway_seconds";
+
+/// Quotes a CSV field when it contains a comma, double quote or newline.
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+fn csv_row(stop: &StopConfig, b: &Bucket) -> String {
+    let opt = |v: Option<i64>| v.map(|v| v.to_string()).unwrap_or_default();
+    let pct = |v: Option<f64>| v.map(|v| format!("{:.1}", v)).unwrap_or_default();
+    [
+        csv_field(stop.stop_id),
+        csv_field(stop.route_id),
+        b.group.to_string(),
+        csv_field(&b.key),
+        b.scheduled.to_string(),
+        b.observed.to_string(),
+        pct(b.pct(b.on_time)),
+        pct(b.pct(b.early)),
+        pct(b.pct(b.late)),
+        opt(b.median_delay),
+        opt(b.p90_delay),
+        opt(b.median_scheduled_headway),
+        opt(b.median_actual_headway),
+        opt(b.p90_actual_headway),
+    ]
+    .join(",")
+}
+
+fn print_accuracy_table(stop: &StopConfig, accuracy: &Accuracy, days: i64, color: bool) {
+    println!(
+        "{}",
+        paint(
+            &format!("{} — {}, prediction error, last {} days", stop.name, stop.route_id, days),
+            STYLE_BOLD,
+            color
+        )
+    );
+    if accuracy.buckets.iter().all(|b| b.samples == 0) {
+        println!("{}", paint("  No recorded predictions for departed trips", STYLE_DIM, color));
+        println!();
+        return;
+    }
+    let header = format!("  {:<8}{:>9}{:>9}{:>18}{:>10}", "Lead", "Samples", "Median", "p10 … p90", "Mean abs");
+    println!("{}", paint(&header, STYLE_BOLD, color));
+    for b in &accuracy.buckets {
+        println!(
+            "  {:<8}{:>9}{:>9}{:>18}{:>10}",
+            format!("{}–{}m", b.from_minutes, b.to_minutes),
+            b.samples,
+            minutes(b.median, true),
+            format!("{} … {}", minutes(b.p10, true), minutes(b.p90, true)),
+            b.mean_abs.map_or_else(|| "–".to_string(), |s| format!("±{:.1}m", s as f64 / 60.0))
+        );
+    }
+    println!();
+}
+
+fn accuracy_json(stop: &StopConfig, accuracy: &Accuracy) -> serde_json::Value {
+    let lead_times: Vec<serde_json::Value> = accuracy
+        .buckets
+        .iter()
+        .map(|b| {
+            serde_json::json!({
+                "from_minutes": b.from_minutes,
+                "to_minutes": b.to_minutes,
+                "samples": b.samples,
+                "median_error_seconds": b.median,
+                "p10_error_seconds": b.p10,
+                "p90_error_seconds": b.p90,
+                "mean_abs_error_seconds": b.mean_abs,
+            })
+        })
+        .collect();
+    serde_json::json!({
+        "name": stop.name,
+        "route_id": stop.route_id,
+        "stop_id": stop.stop_id,
+        "direction_id": stop.direction_id,
+        "lead_times": lead_times,
+    })
+}
+
+const ACCURACY_CSV_HEADER: &str = "stop_id,route_id,lead_from_minutes,lead_to_minutes,samples,\
+median_error_seconds,p10_error_seconds,p90_error_seconds,mean_abs_error_seconds";
+
+/// `report --accuracy`: prediction error (actual minus predicted departure) by lead time.
+fn run_accuracy(
+    db: &Connection,
+    stops: &[StopConfig],
+    options: &Options,
+    since: DateTime<Local>,
+    now: DateTime<Local>,
+    days: i64,
+    color: bool,
+) -> Result<(), Box<dyn Error>> {
+    let mut reports = Vec::new();
+    for stop in stops {
+        reports.push((stop, accuracy::load(db, stop, since, now)?));
+    }
+
+    match options.format {
+        OutputFormat::Json => {
+            let doc = serde_json::json!({
+                "generated_at": now.to_rfc3339(),
+                "since": since.to_rfc3339(),
+                "stops": reports.iter().map(|(stop, a)| accuracy_json(stop, a)).collect::<Vec<_>>(),
+            });
+            println!("{}", serde_json::to_string_pretty(&doc)?);
+        }
+        OutputFormat::Csv => {
+            let opt = |v: Option<i64>| v.map(|v| v.to_string()).unwrap_or_default();
+            println!("{}", ACCURACY_CSV_HEADER);
+            for (stop, accuracy) in &reports {
+                for b in &accuracy.buckets {
+                    println!(
+                        "{},{},{},{},{},{},{},{},{}",
+                        csv_field(stop.stop_id),
+                        csv_field(stop.route_id),
+                        b.from_minutes,
+                        b.to_minutes,
+                        b.samples,
+                        opt(b.median),
+                        opt(b.p10),
+                        opt(b.p90),
+                        opt(b.mean_abs)
+                    );
+                }
+            }
+        }
+        _ => {
+            for (stop, accuracy) in &reports {
+                print_accuracy_table(stop, accuracy, days, color);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Reports on each of `stops` over the last `--since` period, as a table,
+/// CSV (one row per bucket) or JSON.
+pub fn run(stops: &[StopConfig], options: &Options) -> Result<(), Box<dyn Error>> {
+    let db = record::open_db_read_only(options)?;
+    let now = Local::now();
+    let since = now - Duration::minutes(options.since_minutes);
+    let days = (options.since_minutes as f64 / (24.0 * 60.0)).ceil() as i64;
+    let color = options.color.enabled(std::io::stdout().is_terminal());
+
+    if options.accuracy {
+        return run_accuracy(&db, stops, options, since, now, days, color);
+    }
+
+    let mut reports = Vec::new();
+    for stop in stops {
+        let trips = load_trips(&db, stop, since, now)?;
+        let buckets = buckets(&trips);
+        reports.push((stop, trips, buckets));
+    }
+
+    match options.format {
+        OutputFormat::Json => {
+            let doc = serde_json::json!({
+                "generated_at": now.to_rfc3339(),
+                "since": since.to_rfc3339(),
+                "stops": reports
+                    .iter()
+                    .map(|(stop, trips, buckets)| stop_report_json(stop, trips, buckets))
+                    .collect::<Vec<_>>(),
+            });
+            println!("{}", serde_json::to_string_pretty(&doc)?);
+        }
+        OutputFormat::Csv => {
+            println!("{}", CSV_HEADER);
+            for (stop, _, buckets) in &reports {
+                for b in buckets {
+                    println!("{}", csv_row(stop, b));
+                }
+            }
+        }
+        _ => {
+            for (stop, trips, buckets) in &reports {
+                print_table(stop, trips, buckets, days, color);
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,191 @@
+//! Minimal HTTP server for `b60 serve`. Departures are refreshed in the
+//! background every `--watch` interval, and the feed and metrics are rendered
+//! from the latest refresh, so scrapes cost no API requests.
+
+use chrono::{DateTime, Local};
+use futures::stream::{FuturesUnordered, StreamExt};
+use std::cell::RefCell;
+use std::error::Error;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::cli::{Options, DEFAULT_INTERVAL_SECS};
+use crate::provider::Providers;
+use crate::replay;
+use crate::{fetch_filtered_rows, ics, metrics, RowData, StopConfig};
+
+/// How long a client may take to send its request before it is dropped.
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+/// Request headers beyond this many bytes are not read.
+const MAX_REQUEST_BYTES: usize = 8192;
+
+/// The latest background refresh.
+struct Refresh {
+    /// The refresh's `now`, and when it happened
+    now: DateTime<Local>,
+    at: Instant,
+    /// `None` when the MBTA API rate limit was hit
+    rows: Option<Vec<Vec<RowData>>>,
+}
+
+impl Refresh {
+    /// The current time on the refresh's clock, which is the simulated one
+    /// under `--at` or `--replay`.
+    fn now(&self) -> DateTime<Local> {
+        self.now + chrono::Duration::from_std(self.at.elapsed()).unwrap_or_default()
+    }
+}
+
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: String,
+}
+
+impl Response {
+    fn text(status: u16, body: &str) -> Response {
+        Response {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            body: format!("{}\n", body),
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let reason = match self.status {
+            200 => "OK",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            503 => "Service Unavailable",
+            _ => "",
+        };
+        format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.status,
+            reason,
+            self.content_type,
+            self.body.len(),
+            self.body
+        )
+        .into_bytes()
+    }
+}
+
+/// Accepts connections on `options.listen` until the process is stopped.
+/// Connections and the background refresh run concurrently on this task
+/// (providers are not `Send`), so a slow client does not hold up the others.
+pub async fn serve(providers: &Providers, stops: &[StopConfig], options: &Options) -> Result<(), Box<dyn Error>> {
+    let listener = TcpListener::bind(&options.listen).await?;
+    eprintln!("Serving departures on http://{}/departures.ics", options.listen);
+    eprintln!("Serving metrics on http://{}/metrics", options.listen);
+
+    let interval = options
+        .watch
+        .unwrap_or(Duration::from_secs(DEFAULT_INTERVAL_SECS));
+    let latest: RefCell<Option<Refresh>> = RefCell::new(None);
+
+    let refresh = async {
+        loop {
+            let now = replay::now();
+            let rows = fetch_filtered_rows(providers, stops, &options.window, false, now).await;
+            *latest.borrow_mut() = Some(Refresh { now, at: Instant::now(), rows });
+            tokio::time::sleep(interval).await;
+        }
+    };
+
+    let accept = async {
+        let mut connections = FuturesUnordered::new();
+        loop {
+            tokio::select! {
+                accepted = listener.accept() => {
+                    // Errors such as running out of file descriptors pass; keep serving
+                    match accepted {
+                        Ok((socket, _)) => connections.push(handle(socket, &latest, stops)),
+                        Err(e) => eprintln!("⚠️  Accepting a connection failed: {}", e),
+                    }
+                }
+                Some(result) = connections.next(), if !connections.is_empty() => {
+                    if let Err(e) = result {
+                        eprintln!("⚠️  Request failed: {}", e);
+                    }
+                }
+            }
+        }
+    };
+
+    tokio::select! {
+        result = accept => result,
+        _ = refresh => Ok(()),
+    }
+}
+
+async fn handle(
+    mut socket: TcpStream,
+    latest: &RefCell<Option<Refresh>>,
+    stops: &[StopConfig],
+) -> Result<(), Box<dyn Error>> {
+    let head = tokio::time::timeout(READ_TIMEOUT, read_request_head(&mut socket))
+        .await
+        .map_err(|_| "timed out waiting for the request")??;
+    let request = String::from_utf8_lossy(&head);
+
+    // Only the request line matters: "GET /path?query HTTP/1.1"
+    let mut parts = request.split_whitespace();
+    let method = parts.next().unwrap_or("");
+    let target = parts.next().unwrap_or("/");
+    let path = target.split('?').next().unwrap_or(target);
+
+    let response = if method != "GET" {
+        Response::text(405, "method not allowed")
+    } else {
+        route(path, latest.borrow().as_ref(), stops)
+    };
+
+    socket.write_all(&response.to_bytes()).await?;
+    socket.shutdown().await?;
+    Ok(())
+}
+
+/// Reads until the blank line ending the request headers, the client closing
+/// the connection, or `MAX_REQUEST_BYTES`.
+async fn read_request_head(socket: &mut TcpStream) -> std::io::Result<Vec<u8>> {
+    let mut head = Vec::new();
+    let mut buf = [0u8; 1024];
+    while head.len() < MAX_REQUEST_BYTES && !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = socket.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+    Ok(head)
+}
+
+fn route(path: &str, latest: Option<&Refresh>, stops: &[StopConfig]) -> Response {
+    match path {
+        "/departures.ics" => match latest.map(|l| (l.rows.as_ref(), l.now())) {
+            Some((Some(rows), now)) => {
+                let stops: Vec<&StopConfig> = stops.iter().collect();
+                Response {
+                    status: 200,
+                    content_type: "text/calendar; charset=utf-8",
+                    body: ics::calendar(&stops, rows, now),
+                }
+            }
+            Some((None, _)) => Response::text(503, "MBTA API rate limit exceeded"),
+            None => Response::text(503, "departures not fetched yet"),
+        },
+        "/metrics" => {
+            // Counters are still worth scraping while departures are rate limited
+            let rows = latest.and_then(|l| l.rows.as_deref());
+            let now = latest.map_or_else(Local::now, Refresh::now);
+            Response {
+                status: 200,
+                content_type: "text/plain; version=0.0.4; charset=utf-8",
+                body: metrics::render(stops, rows, now),
+            }
+        }
+        _ => Response::text(404, "not found"),
+    }
+}
@@ -0,0 +1,185 @@
+//! Single-line output for status bars: plain text for tmux, i3blocks and
+//! polybar, waybar's custom-module JSON, and the i3bar protocol.
+
+use chrono::{DateTime, Local};
+
+use crate::cli::Layout;
+use crate::{
+    format_stop_data, DelayStatus, DisplayOptions, RowData, StopConfig, WindowSettings,
+    IMMINENT_MINUTES,
+};
+
+/// Sent once before the first i3bar update; each update is then an array element.
+pub const I3BAR_HEADER: &str = "{\"version\":1}\n[";
+
+/// Short route label: "60" for buses, "D" for Green Line branches.
+fn route_label(route_id: &str) -> &str {
+    route_id.strip_prefix("Green-").unwrap_or(route_id)
+}
+
+fn direction_arrow(direction_id: i32) -> &'static str {
+    if direction_id == 0 { "↗" } else { "↙" }
+}
+
+/// Trips shown for a stop: those still to depart, up to the stop's trip count.
+/// Departed trips kept by `--past` are left to the grid.
+fn shown_rows<'a>(rows: &'a [RowData], window: &WindowSettings, now: DateTime<Local>) -> impl Iterator<Item = &'a RowData> {
+    rows.iter()
+        .filter(move |r| r.pred_dt.or(r.sched_dt).is_some_and(|t| t >= now))
+        .take(window.trips)
+}
+
+/// "60↗ 3m·11m" for one stop; scheduled-only times are prefixed with "~".
+fn stop_segment(stop: &StopConfig, rows: &[RowData], window: &WindowSettings, now: DateTime<Local>) -> Option<String> {
+    let times: Vec<String> = shown_rows(rows, window, now)
+        .filter_map(|r| {
+            let (t, live) = match (r.pred_dt, r.sched_dt) {
+                (Some(pred), _) => (pred, true),
+                (None, Some(sched)) => (sched, false),
+                (None, None) => return None,
+            };
+            let minutes = t.signed_duration_since(now).num_minutes();
+            let text = if minutes < 1 { "now".to_string() } else { format!("{}m", minutes) };
+            Some(if live { text } else { format!("~{}", text) })
+        })
+        .collect();
+
+    if times.is_empty() {
+        return None;
+    }
+    Some(format!(
+        "{}{} {}",
+        route_label(stop.route_id),
+        direction_arrow(stop.direction_id),
+        times.join("·")
+    ))
+}
+
+/// Condenses all stops into one line, e.g. "60↗ 3m·11m | D↙ 2m".
+pub fn status_line(stops: &[&StopConfig], rows: &[Vec<RowData>], window: &WindowSettings, now: DateTime<Local>) -> String {
+    let segments: Vec<String> = stops
+        .iter()
+        .zip(rows)
+        .filter_map(|(stop, rows)| stop_segment(stop, rows, &window.for_stop(stop), now))
+        .collect();
+
+    if segments.is_empty() {
+        "no trips".to_string()
+    } else {
+        segments.join(" | ")
+    }
+}
+
+/// State classes derived from each stop's next trip: "imminent", "late", "nodata".
+fn classes(stops: &[&StopConfig], rows: &[Vec<RowData>], window: &WindowSettings, now: DateTime<Local>) -> Vec<&'static str> {
+    let mut classes = Vec::new();
+    let next_trips: Vec<&RowData> = stops
+        .iter()
+        .zip(rows)
+        .filter_map(|(stop, rows)| shown_rows(rows, &window.for_stop(stop), now).next())
+        .collect();
+
+    if next_trips.is_empty() {
+        classes.push("nodata");
+    }
+    if next_trips
+        .iter()
+        .any(|r| r.pred_dt.is_some_and(|t| t.signed_duration_since(now).num_minutes() < IMMINENT_MINUTES))
+    {
+        classes.push("imminent");
+    }
+    if next_trips.iter().any(|r| {
+        r.delay_seconds()
+            .is_some_and(|d| matches!(DelayStatus::from_delay(d), DelayStatus::Late | DelayStatus::VeryLate))
+    }) {
+        classes.push("late");
+    }
+    classes
+}
+
+/// Multi-line detail for hover text, in the same wording as the grid.
+fn tooltip(stops: &[&StopConfig], rows: &[Vec<RowData>], window: &WindowSettings, now: DateTime<Local>) -> String {
+    let display = DisplayOptions {
+        color: false,
+        layout: Layout::List,
+        width: None,
+        bunch_minutes: None,
+    };
+    stops
+        .iter()
+        .zip(rows)
+        .map(|(stop, rows)| {
+            let stop_display = format_stop_data(stop.name, rows, &window.for_stop(stop), None, None, &display, now);
+            let mut lines = vec![stop_display.name];
+            lines.extend(stop_display.times.iter().map(|t| format!("  {}", t)));
+            lines.join("\n")
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// A waybar custom-module update (`"return-type": "json"`).
+pub fn waybar(stops: &[&StopConfig], rows: &[Vec<RowData>], window: &WindowSettings, now: DateTime<Local>) -> String {
+    serde_json::json!({
+        "text": status_line(stops, rows, window, now),
+        "tooltip": tooltip(stops, rows, window, now),
+        "class": classes(stops, rows, window, now),
+    })
+    .to_string()
+}
+
+/// One i3bar protocol status line (an array holding a single block).
+pub fn i3bar(stops: &[&StopConfig], rows: &[Vec<RowData>], window: &WindowSettings, now: DateTime<Local>) -> String {
+    let classes = classes(stops, rows, window, now);
+    let color = if classes.contains(&"late") {
+        Some("#DA291C")
+    } else if classes.contains(&"nodata") {
+        Some("#888888")
+    } else {
+        None
+    };
+    serde_json::json!([{
+        "name": "b60",
+        "full_text": status_line(stops, rows, window, now),
+        "color": color,
+        "urgent": classes.contains(&"imminent"),
+    }])
+    .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration, TimeZone};
+
+    fn row(trip_id: &str, pred_dt: DateTime<Local>) -> RowData {
+        RowData {
+            trip_id: trip_id.to_string(),
+            headsign: None,
+            sched_dt: Some(pred_dt),
+            pred_dt: Some(pred_dt),
+            stops_away: None,
+            wheelchair_accessible: None,
+        }
+    }
+
+    #[test]
+    fn departed_trips_are_not_shown_or_urgent() {
+        let stop = StopConfig {
+            name: "Test",
+            route_id: "60",
+            stop_id: "1",
+            direction_id: 0,
+            is_origin: false,
+            window: Default::default(),
+            walk_minutes: None,
+            provider: "mbta",
+        };
+        let now = Local.with_ymd_and_hms(2026, 10, 18, 12, 0, 0).unwrap();
+        let window = WindowSettings { past_cutoff_minutes: 5, ..Default::default() };
+        let rows = vec![vec![row("T1", now - Duration::minutes(2)), row("T2", now + Duration::minutes(7))]];
+
+        assert_eq!(status_line(&[&stop], &rows, &window, now), "60↗ 7m");
+        assert!(classes(&[&stop], &rows, &window, now).is_empty());
+    }
+}
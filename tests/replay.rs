@@ -0,0 +1,30 @@
+//! Runs `b60` against a recorded refresh. The recording was made in Boston and
+//! both the schedule query and the times shown are local, so the child process
+//! is given that zone; the test process's own environment is left alone.
+
+use std::process::Command;
+
+#[test]
+fn replays_a_recorded_refresh() {
+    let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/replay");
+    let output = Command::new(env!("CARGO_BIN_EXE_b60"))
+        .args(["--replay", dir, "--stop", "1519", "--color", "never"])
+        .env("TZ", "America/New_York")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<&str> = stdout.lines().map(str::trim_end).collect();
+    assert_eq!(
+        lines,
+        [
+            "Route 60:",
+            "Brookline Ave @ Fullerton (outbound)",
+            "🟢 13:21:00 (in 6m) +2m late (2 stops)",
+            "📅 13:34 (in 19m)",
+            "📅 13:49 (in 34m)",
+            "",
+        ]
+    );
+}